@@ -1,24 +1,36 @@
-
 #!/bin/sh
-PW=`cat secrets/password.txt`
+EMAIL="your@email.com"
+PW="change-this-password"
 CREDS="{
-  \"email\": \"your@email.com\",
+  \"email\": \"$EMAIL\",
   \"full_name\": \"Some name\",
   \"password\": \"$PW\"
 }"
 
-ACCESS_TOKEN=`curl -s -X POST -H "Content-type: application/json" \
-     -d "$CREDS" \
-     http://localhost:3000/api/v1/register | jq .access_token | sed 's/"//g'`
+# With an API key (POST /api/v1/api-keys, scope "write") in RS_API_KEY there
+# is no need to log in. Otherwise this creates the account on first use and
+# later runs just log in. Adding recipes needs the contributor role: the
+# first account on a server is an admin, and an admin can promote others
+# through PUT /api/v1/users/{id}/role.
+if [ -n "$RS_API_KEY" ]; then
+  ACCESS_TOKEN="$RS_API_KEY"
+else
+  curl -s -X POST -H "Content-type: application/json" \
+       -d "$CREDS" \
+       http://localhost:3000/api/v1/register > /dev/null
+  ACCESS_TOKEN=`curl -s -X POST -H "Content-type: application/json" \
+       -d "$CREDS" \
+       http://localhost:3000/api/v1/login | jq .access_token | sed 's/"//g'`
+fi
 
 JOKE='{
-  "id": 40,
   "title": "Sugar milk",
   "category": "drink",
   "ingredient_amount": [
     "100ml milk", "20g sugar"
   ],
-  "preparation": "put the sugar in the milk and stir"
+  "preparation": "put the sugar in the milk and stir",
+  "servings": 1
 }'
 
 curl -X POST -H "Content-type: application/json"  \
@@ -6,8 +6,6 @@ use cookie::*;
 use finder::*;
 use recipe::*;
 
-use std::collections::HashSet;
-
 extern crate serde;
 // use gloo_console::log;
 use gloo_net::http;
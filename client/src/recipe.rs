@@ -1,11 +1,16 @@
 use crate::*;
 
+#[derive(Clone, PartialEq, serde::Deserialize)]
+pub struct IngredientStruct {
+    pub text: String,
+}
+
 #[derive(Properties, Clone, PartialEq, serde::Deserialize)]
 pub struct RecipeStruct {
     pub id: i64,
     pub title: String,
     pub category: String,
-    pub ingredient_amount: Option<HashSet<String>>,
+    pub ingredient_amount: Option<Vec<IngredientStruct>>,
     pub preparation: Option<String>,
 }
 
@@ -22,8 +27,8 @@ impl RecipeStruct {
         }
     }
 }
-pub fn format_ingredients(ingredients: &HashSet<String>) -> String {
-    let ingredients_list: Vec<&str> = ingredients.iter().map(String::as_ref).collect();
+pub fn format_ingredients(ingredients: &[IngredientStruct]) -> String {
+    let ingredients_list: Vec<&str> = ingredients.iter().map(|i| i.text.as_ref()).collect();
     ingredients_list.join(", ")
 }
 
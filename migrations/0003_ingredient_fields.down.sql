@@ -0,0 +1,4 @@
+ALTER TABLE ingredients DROP COLUMN name;
+ALTER TABLE ingredients DROP COLUMN unit;
+ALTER TABLE ingredients DROP COLUMN quantity_max;
+ALTER TABLE ingredients DROP COLUMN quantity;
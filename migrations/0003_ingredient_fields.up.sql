@@ -0,0 +1,6 @@
+-- Split ingredient_amount into structured columns. Existing rows keep
+-- name = '' until the server parses them on startup.
+ALTER TABLE ingredients ADD COLUMN quantity REAL;
+ALTER TABLE ingredients ADD COLUMN quantity_max REAL;
+ALTER TABLE ingredients ADD COLUMN unit VARCHAR(50);
+ALTER TABLE ingredients ADD COLUMN name VARCHAR(200) NOT NULL DEFAULT '';
@@ -0,0 +1,2 @@
+-- Number of servings the ingredient amounts are written for, when known.
+ALTER TABLE recipes ADD COLUMN servings INTEGER;
@@ -0,0 +1,51 @@
+-- Full-text index over recipes, one row per recipe with rowid = recipes.id.
+-- Triggers keep it in step with both recipes and ingredients.
+CREATE VIRTUAL TABLE IF NOT EXISTS recipes_fts USING fts5(
+  title,
+  category,
+  preparation,
+  ingredients,
+  tokenize = 'porter unicode61'
+);
+
+INSERT INTO recipes_fts (rowid, title, category, preparation, ingredients)
+SELECT r.id, r.title, r.category, r.preparation,
+  coalesce((SELECT group_concat(i.ingredient_amount, ', ')
+            FROM ingredients i WHERE i.recipe_id = r.id), '')
+FROM recipes r;
+
+CREATE TRIGGER IF NOT EXISTS recipes_fts_insert AFTER INSERT ON recipes BEGIN
+  INSERT INTO recipes_fts (rowid, title, category, preparation, ingredients)
+  VALUES (new.id, new.title, new.category, new.preparation, '');
+END;
+
+CREATE TRIGGER IF NOT EXISTS recipes_fts_update AFTER UPDATE ON recipes BEGIN
+  UPDATE recipes_fts
+  SET title = new.title, category = new.category, preparation = new.preparation
+  WHERE rowid = old.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS recipes_fts_delete AFTER DELETE ON recipes BEGIN
+  DELETE FROM recipes_fts WHERE rowid = old.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS ingredients_fts_insert AFTER INSERT ON ingredients BEGIN
+  UPDATE recipes_fts
+  SET ingredients = coalesce((SELECT group_concat(ingredient_amount, ', ')
+                              FROM ingredients WHERE recipe_id = new.recipe_id), '')
+  WHERE rowid = CAST(new.recipe_id AS INTEGER);
+END;
+
+CREATE TRIGGER IF NOT EXISTS ingredients_fts_update AFTER UPDATE ON ingredients BEGIN
+  UPDATE recipes_fts
+  SET ingredients = coalesce((SELECT group_concat(ingredient_amount, ', ')
+                              FROM ingredients WHERE recipe_id = new.recipe_id), '')
+  WHERE rowid = CAST(new.recipe_id AS INTEGER);
+END;
+
+CREATE TRIGGER IF NOT EXISTS ingredients_fts_delete AFTER DELETE ON ingredients BEGIN
+  UPDATE recipes_fts
+  SET ingredients = coalesce((SELECT group_concat(ingredient_amount, ', ')
+                              FROM ingredients WHERE recipe_id = old.recipe_id), '')
+  WHERE rowid = CAST(old.recipe_id AS INTEGER);
+END;
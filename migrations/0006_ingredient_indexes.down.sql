@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS ingredients_ingredient_amount;
+DROP INDEX IF EXISTS ingredients_name;
+DROP INDEX IF EXISTS ingredients_recipe_id;
@@ -0,0 +1,3 @@
+CREATE INDEX IF NOT EXISTS ingredients_recipe_id ON ingredients (recipe_id);
+CREATE INDEX IF NOT EXISTS ingredients_name ON ingredients (name);
+CREATE INDEX IF NOT EXISTS ingredients_ingredient_amount ON ingredients (ingredient_amount);
@@ -0,0 +1,48 @@
+DROP TRIGGER IF EXISTS recipe_ingredients_fts_insert;
+DROP TRIGGER IF EXISTS recipe_ingredients_fts_update;
+DROP TRIGGER IF EXISTS recipe_ingredients_fts_delete;
+
+CREATE TABLE ingredients_flat (
+  recipe_id VARCHAR(200) NOT NULL,
+  ingredient_amount VARCHAR(200) NOT NULL,
+  quantity REAL,
+  quantity_max REAL,
+  unit VARCHAR(50),
+  name VARCHAR(200) NOT NULL DEFAULT '',
+  FOREIGN KEY (recipe_id) REFERENCES recipes(id)
+);
+
+INSERT INTO ingredients_flat
+  (recipe_id, ingredient_amount, quantity, quantity_max, unit, name)
+SELECT ri.recipe_id, ri.ingredient_amount, ri.quantity, ri.quantity_max, ri.unit, coalesce(i.name, '')
+FROM recipe_ingredients ri LEFT JOIN ingredients i ON i.id = ri.ingredient_id
+ORDER BY ri.id;
+
+DROP TABLE recipe_ingredients;
+DROP TABLE ingredients;
+ALTER TABLE ingredients_flat RENAME TO ingredients;
+
+CREATE INDEX ingredients_recipe_id ON ingredients (recipe_id);
+CREATE INDEX ingredients_name ON ingredients (name);
+CREATE INDEX ingredients_ingredient_amount ON ingredients (ingredient_amount);
+
+CREATE TRIGGER ingredients_fts_insert AFTER INSERT ON ingredients BEGIN
+  UPDATE recipes_fts
+  SET ingredients = coalesce((SELECT group_concat(ingredient_amount, ', ')
+                              FROM ingredients WHERE recipe_id = new.recipe_id), '')
+  WHERE rowid = CAST(new.recipe_id AS INTEGER);
+END;
+
+CREATE TRIGGER ingredients_fts_update AFTER UPDATE ON ingredients BEGIN
+  UPDATE recipes_fts
+  SET ingredients = coalesce((SELECT group_concat(ingredient_amount, ', ')
+                              FROM ingredients WHERE recipe_id = new.recipe_id), '')
+  WHERE rowid = CAST(new.recipe_id AS INTEGER);
+END;
+
+CREATE TRIGGER ingredients_fts_delete AFTER DELETE ON ingredients BEGIN
+  UPDATE recipes_fts
+  SET ingredients = coalesce((SELECT group_concat(ingredient_amount, ', ')
+                              FROM ingredients WHERE recipe_id = old.recipe_id), '')
+  WHERE rowid = CAST(old.recipe_id AS INTEGER);
+END;
@@ -0,0 +1,77 @@
+-- Split the old ingredients table into a dictionary of ingredient names and
+-- a recipe_ingredients join table keyed by integer ids. The server runs with
+-- foreign_keys enabled, so deleting a recipe removes its ingredient rows.
+DROP TRIGGER IF EXISTS ingredients_fts_insert;
+DROP TRIGGER IF EXISTS ingredients_fts_update;
+DROP TRIGGER IF EXISTS ingredients_fts_delete;
+
+ALTER TABLE ingredients RENAME TO ingredients_old;
+DROP INDEX IF EXISTS ingredients_recipe_id;
+DROP INDEX IF EXISTS ingredients_name;
+DROP INDEX IF EXISTS ingredients_ingredient_amount;
+
+CREATE TABLE ingredients (
+  id INTEGER PRIMARY KEY NOT NULL,
+  name VARCHAR(200) NOT NULL UNIQUE
+);
+
+-- ingredient_id is NULL only for rows written before ingredients were
+-- parsed; the server fills it in on startup.
+CREATE TABLE recipe_ingredients (
+  id INTEGER PRIMARY KEY NOT NULL,
+  recipe_id INTEGER NOT NULL REFERENCES recipes (id) ON DELETE CASCADE,
+  ingredient_id INTEGER REFERENCES ingredients (id),
+  ingredient_amount VARCHAR(200) NOT NULL,
+  quantity REAL,
+  quantity_max REAL,
+  unit VARCHAR(50)
+);
+
+CREATE INDEX recipe_ingredients_recipe_id ON recipe_ingredients (recipe_id);
+CREATE INDEX recipe_ingredients_ingredient_id ON recipe_ingredients (ingredient_id);
+CREATE INDEX recipe_ingredients_ingredient_amount ON recipe_ingredients (ingredient_amount);
+
+INSERT INTO ingredients (name)
+SELECT DISTINCT name FROM ingredients_old WHERE name <> '';
+
+-- recipe_ingredients.recipe_id must name a recipe, which the old table did
+-- not enforce. Rather than drop rows whose recipe is gone, stop here with
+-- "CHECK constraint failed: ingredients_without_recipe"; delete those rows
+-- from ingredients, or restore their recipes, and run the migration again.
+CREATE TEMP TABLE orphan_check (
+  orphans INTEGER NOT NULL CONSTRAINT ingredients_without_recipe CHECK (orphans = 0)
+);
+INSERT INTO orphan_check
+SELECT COUNT(*) FROM ingredients_old o
+WHERE NOT EXISTS (SELECT 1 FROM recipes r WHERE r.id = CAST(o.recipe_id AS INTEGER));
+DROP TABLE orphan_check;
+
+INSERT INTO recipe_ingredients
+  (recipe_id, ingredient_id, ingredient_amount, quantity, quantity_max, unit)
+SELECT CAST(o.recipe_id AS INTEGER), i.id, o.ingredient_amount, o.quantity, o.quantity_max, o.unit
+FROM ingredients_old o
+LEFT JOIN ingredients i ON i.name = o.name
+ORDER BY o.rowid;
+
+DROP TABLE ingredients_old;
+
+CREATE TRIGGER recipe_ingredients_fts_insert AFTER INSERT ON recipe_ingredients BEGIN
+  UPDATE recipes_fts
+  SET ingredients = coalesce((SELECT group_concat(ingredient_amount, ', ')
+                              FROM recipe_ingredients WHERE recipe_id = new.recipe_id), '')
+  WHERE rowid = new.recipe_id;
+END;
+
+CREATE TRIGGER recipe_ingredients_fts_update AFTER UPDATE OF ingredient_amount ON recipe_ingredients BEGIN
+  UPDATE recipes_fts
+  SET ingredients = coalesce((SELECT group_concat(ingredient_amount, ', ')
+                              FROM recipe_ingredients WHERE recipe_id = new.recipe_id), '')
+  WHERE rowid = new.recipe_id;
+END;
+
+CREATE TRIGGER recipe_ingredients_fts_delete AFTER DELETE ON recipe_ingredients BEGIN
+  UPDATE recipes_fts
+  SET ingredients = coalesce((SELECT group_concat(ingredient_amount, ', ')
+                              FROM recipe_ingredients WHERE recipe_id = old.recipe_id), '')
+  WHERE rowid = old.recipe_id;
+END;
@@ -0,0 +1,8 @@
+-- Accounts that can sign in to the API. Emails compare case-insensitively.
+CREATE TABLE IF NOT EXISTS users (
+  id INTEGER PRIMARY KEY NOT NULL,
+  full_name VARCHAR(200) NOT NULL,
+  email VARCHAR(200) NOT NULL UNIQUE COLLATE NOCASE,
+  password_hash VARCHAR(200) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
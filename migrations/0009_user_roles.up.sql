@@ -0,0 +1,6 @@
+-- What each account may do, from least to most: reader, contributor, editor,
+-- admin. Existing accounts keep the write access they had as contributors;
+-- admins are named at startup with --admin-email.
+ALTER TABLE users ADD COLUMN role VARCHAR(20) NOT NULL DEFAULT 'reader'
+  CHECK (role IN ('reader', 'contributor', 'editor', 'admin'));
+UPDATE users SET role = 'contributor';
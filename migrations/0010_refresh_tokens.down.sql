@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS revoked_tokens;
+DROP INDEX IF EXISTS refresh_tokens_family;
+DROP TABLE IF EXISTS refresh_tokens;
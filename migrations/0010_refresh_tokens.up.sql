@@ -0,0 +1,19 @@
+-- Refresh tokens, stored as hashes. Each refresh replaces the token with a
+-- new one in the same family; presenting a replaced token again revokes the
+-- whole family. Times are unix seconds.
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+  id INTEGER PRIMARY KEY NOT NULL,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  token_hash VARCHAR(64) NOT NULL UNIQUE,
+  family VARCHAR(64) NOT NULL,
+  expires_at INTEGER NOT NULL,
+  revoked_at INTEGER
+);
+CREATE INDEX refresh_tokens_family ON refresh_tokens (family);
+
+-- Access tokens revoked before they expire, by `jti`. Rows can go once the
+-- token would have expired anyway.
+CREATE TABLE IF NOT EXISTS revoked_tokens (
+  jti VARCHAR(64) PRIMARY KEY NOT NULL,
+  expires_at INTEGER NOT NULL
+);
@@ -0,0 +1,14 @@
+-- Long-lived keys for scripts, stored as hashes. `prefix` is the start of
+-- the key, kept so owners can tell their keys apart. Times are unix seconds.
+CREATE TABLE IF NOT EXISTS api_keys (
+  id INTEGER PRIMARY KEY NOT NULL,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  name VARCHAR(200) NOT NULL,
+  prefix VARCHAR(16) NOT NULL,
+  key_hash VARCHAR(64) NOT NULL UNIQUE,
+  scope VARCHAR(10) NOT NULL CHECK (scope IN ('read', 'write')),
+  created_at INTEGER NOT NULL,
+  last_used_at INTEGER,
+  revoked_at INTEGER
+);
+CREATE INDEX api_keys_user_id ON api_keys (user_id);
@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS recipes_author_id;
+ALTER TABLE recipes DROP COLUMN updated_at;
+ALTER TABLE recipes DROP COLUMN created_at;
+ALTER TABLE recipes DROP COLUMN author_id;
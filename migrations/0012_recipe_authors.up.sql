@@ -0,0 +1,9 @@
+-- Who added each recipe and when it was added and last changed. Recipes
+-- from before this have no author and count as created now.
+ALTER TABLE recipes ADD COLUMN author_id INTEGER REFERENCES users(id) ON DELETE SET NULL;
+ALTER TABLE recipes ADD COLUMN created_at TIMESTAMP;
+ALTER TABLE recipes ADD COLUMN updated_at TIMESTAMP;
+UPDATE recipes
+SET created_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now'),
+    updated_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now');
+CREATE INDEX recipes_author_id ON recipes (author_id);
@@ -0,0 +1,6 @@
+DROP TRIGGER IF EXISTS audit_log_no_delete;
+DROP TRIGGER IF EXISTS audit_log_no_update;
+DROP INDEX IF EXISTS audit_log_at;
+DROP INDEX IF EXISTS audit_log_action_target;
+DROP INDEX IF EXISTS audit_log_actor_id;
+DROP TABLE IF EXISTS audit_log;
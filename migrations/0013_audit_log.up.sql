@@ -0,0 +1,22 @@
+-- Every change to recipes, accounts and sessions, newest last. `actor_id` is
+-- the `sub` of the caller, if known; `diff` holds the fields that changed as
+-- {"field": {"before": ..., "after": ...}}. Rows can only be added.
+CREATE TABLE IF NOT EXISTS audit_log (
+  id INTEGER PRIMARY KEY NOT NULL,
+  at TIMESTAMP NOT NULL,
+  actor_id INTEGER,
+  action VARCHAR(50) NOT NULL,
+  target_id INTEGER,
+  request_id VARCHAR(64),
+  diff TEXT
+);
+CREATE INDEX audit_log_actor_id ON audit_log (actor_id);
+CREATE INDEX audit_log_action_target ON audit_log (action, target_id);
+CREATE INDEX audit_log_at ON audit_log (at);
+
+CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log BEGIN
+  SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
+CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log BEGIN
+  SELECT RAISE(ABORT, 'audit_log is append-only');
+END;
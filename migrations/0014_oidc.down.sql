@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS oidc_logins;
+DROP INDEX IF EXISTS oidc_identities_user_id;
+DROP TABLE IF EXISTS oidc_identities;
@@ -0,0 +1,23 @@
+-- Accounts signed in through the OpenID Connect identity provider, by the
+-- provider's issuer and subject. Such accounts may have an empty password
+-- hash, which never verifies, so they can only sign in through the provider.
+CREATE TABLE IF NOT EXISTS oidc_identities (
+  issuer VARCHAR(200) NOT NULL,
+  subject VARCHAR(200) NOT NULL,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  PRIMARY KEY (issuer, subject)
+);
+CREATE INDEX oidc_identities_user_id ON oidc_identities (user_id);
+
+-- Logins sent to the identity provider and not yet returned, by the `state`
+-- parameter. Holds the PKCE verifier and the nonce expected in the ID token,
+-- and the account a login was started from to link the identity to it, rather
+-- than to sign in as whichever account the identity maps to. Times are unix
+-- seconds.
+CREATE TABLE IF NOT EXISTS oidc_logins (
+  state VARCHAR(64) PRIMARY KEY NOT NULL,
+  code_verifier VARCHAR(128) NOT NULL,
+  nonce VARCHAR(64) NOT NULL,
+  expires_at INTEGER NOT NULL,
+  link_user_id INTEGER REFERENCES users(id) ON DELETE CASCADE
+);
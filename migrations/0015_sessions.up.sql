@@ -0,0 +1,10 @@
+-- Browser sessions for the HTML pages, by hash of the id in the session
+-- cookie. Each has the CSRF token that its pages' forms must send back.
+-- Times are unix seconds.
+CREATE TABLE IF NOT EXISTS sessions (
+  id_hash VARCHAR(64) PRIMARY KEY NOT NULL,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  csrf_token VARCHAR(64) NOT NULL,
+  expires_at INTEGER NOT NULL
+);
+CREATE INDEX sessions_user_id ON sessions (user_id);
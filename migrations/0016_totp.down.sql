@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS recovery_codes_user_id;
+DROP TABLE IF EXISTS recovery_codes;
+DROP TABLE IF EXISTS user_totp;
@@ -0,0 +1,19 @@
+-- Authenticator app (TOTP) second factor. The secret is base32 as shown to
+-- the user; it only counts once confirmed with a first code. `last_step` is
+-- the time step of the last accepted code, so no code is accepted twice.
+CREATE TABLE IF NOT EXISTS user_totp (
+  user_id INTEGER PRIMARY KEY NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  secret VARCHAR(64) NOT NULL,
+  confirmed_at INTEGER,
+  last_step INTEGER
+);
+
+-- One-time recovery codes for signing in without the authenticator, stored
+-- as hashes. Times are unix seconds.
+CREATE TABLE IF NOT EXISTS recovery_codes (
+  id INTEGER PRIMARY KEY NOT NULL,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  code_hash VARCHAR(64) NOT NULL UNIQUE,
+  used_at INTEGER
+);
+CREATE INDEX recovery_codes_user_id ON recovery_codes (user_id);
@@ -0,0 +1,9 @@
+-- Recent failed authentication attempts, by what they were made from or
+-- against: `ip:<address>`, `account:<email>` or `user:<id>`. Failures are
+-- forgotten a while after the last one. Times are unix seconds.
+CREATE TABLE IF NOT EXISTS auth_failures (
+  key VARCHAR(250) PRIMARY KEY NOT NULL,
+  failures INTEGER NOT NULL,
+  last_failure_at INTEGER NOT NULL,
+  locked_until INTEGER
+);
@@ -0,0 +1,3 @@
+-- Counts the changes to each recipe, to tag what a client has seen and to
+-- refuse updates made against an outdated copy.
+ALTER TABLE recipes ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
@@ -1,5 +1,7 @@
 use crate::*;
 
+use axum_extra::extract::cookie::CookieJar;
+
 #[derive(OpenApi)]
 #[openapi(
     tags(
@@ -10,68 +12,196 @@ pub struct ApiDoc;
 
 pub fn router() -> OpenApiRouter<Arc<RwLock<AppState>>> {
     OpenApiRouter::new()
-        .routes(routes!(get_recipe))
+        .routes(routes!(get_recipe, update_recipe, patch_recipe, delete_recipe))
+        .routes(routes!(list_recipes))
         .routes(routes!(get_recipe_by_ingredients))
         .routes(routes!(get_random_recipe))
+        .routes(routes!(search_recipes))
+        .routes(routes!(get_recipe_search, post_recipe_search))
+        .routes(routes!(get_recipes_by_pantry))
         .routes(routes!(register))
+        .routes(routes!(login))
+        .routes(routes!(oidc_login))
+        .routes(routes!(oidc_callback))
+        .routes(routes!(oidc_link))
+        .routes(routes!(refresh_token))
+        .routes(routes!(logout))
+        .routes(routes!(set_user_role))
+        .routes(routes!(create_api_key, list_api_keys))
+        .routes(routes!(revoke_api_key))
+        .routes(routes!(enroll_totp))
+        .routes(routes!(confirm_totp))
+        .routes(routes!(disable_totp))
+        .routes(routes!(reset_user_totp))
+        .routes(routes!(get_audit_log))
         .routes(routes!(add_recipe))
 }
 
-async fn get_recipe_by_id(db: &SqlitePool, recipe_id: &str) -> Result<response::Response, http::StatusCode> {
-    let recipe_result = recipe::get(db, recipe_id).await;
-    match recipe_result {
-        Ok((recipe, ingredients)) => Ok(JsonRecipe::new(recipe, ingredients).into_response()),
-        Err(e) => {
-            log::warn!("recipe fetch failed: {}", e);
-            Err(http::StatusCode::NOT_FOUND)
+#[derive(Debug, Default, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RecipeParams {
+    /// Scale ingredient quantities to this many servings.
+    #[param(minimum = 1, example = 6)]
+    servings: Option<i64>,
+    /// Convert quantities and oven temperatures to this unit system.
+    units: Option<UnitSystem>,
+}
+
+/// Conditional GET headers, under which a client's cached copy of a recipe
+/// is answered with 304 Not Modified instead of the recipe. Only
+/// `If-None-Match` is answered: the ETag changes when the author is renamed
+/// too, which `Last-Modified` does not show, so a copy current by
+/// `If-Modified-Since` may be stale.
+#[derive(Default)]
+struct Conditions {
+    if_none_match: Option<headers::IfNoneMatch>,
+}
+
+impl Conditions {
+    fn new(request_headers: &http::HeaderMap) -> Self {
+        // An absent list header decodes as an empty list, which would match
+        // nothing rather than not apply.
+        let if_none_match = match request_headers.contains_key(http::header::IF_NONE_MATCH) {
+            true => request_headers.typed_get(),
+            false => None,
+        };
+        Conditions { if_none_match }
+    }
+
+    /// Whether the copy tagged `etag` is still current.
+    fn not_modified(&self, etag: &headers::ETag) -> bool {
+        self.if_none_match
+            .as_ref()
+            .is_some_and(|if_none_match| !if_none_match.precondition_passes(etag))
+    }
+}
+
+async fn get_recipe_by_id(
+    db: &SqlitePool,
+    recipe_id: &str,
+    params: &RecipeParams,
+    conditions: &Conditions,
+) -> Result<response::Response, ApiError> {
+    let (recipe, ingredients) = match recipe::get(db, recipe_id).await {
+        Err(sqlx::Error::RowNotFound) => {
+            return Err(ApiError::NotFound(format!("no recipe with id {}", recipe_id)));
         }
+        result => result?,
+    };
+    let etag = recipe::etag(recipe.id, recipe.version, recipe.author.as_deref());
+    let last_modified = recipe
+        .updated_at
+        .map(|updated_at| headers::LastModified::from(std::time::SystemTime::from(updated_at)));
+
+    let mut response = if conditions.not_modified(&etag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let mut recipe = JsonRecipe::new(recipe, ingredients);
+        if let Some(servings) = params.servings
+            && (servings < 1 || !recipe.scale(servings))
+        {
+            return Err(ApiError::Validation(format!(
+                "recipe {} cannot be scaled to {} servings",
+                recipe_id, servings
+            )));
+        }
+        if let Some(system) = params.units {
+            recipe.convert_units(system);
+        }
+        recipe.into_response()
+    };
+    response.headers_mut().typed_insert(etag);
+    if let Some(last_modified) = last_modified {
+        response.headers_mut().typed_insert(last_modified);
     }
+    Ok(response)
 }
 
 #[utoipa::path(
     get,
     path = "/recipe/{recipe_id}",
+    params(RecipeParams),
     responses(
-        (status = 200, description = "Get a recipe by id", body = [JsonRecipe]),
-        (status = 404, description = "No matching recipe"),
+        (status = 200, description = "Get a recipe by id", body = [JsonRecipe],
+            headers(
+                ("ETag" = String, description = "Tag of the recipe's current version"),
+                ("Last-Modified" = String, description = "When the recipe last changed, not counting its author's name"),
+            )),
+        (status = 304, description = "The copy named by If-None-Match is current"),
+        (status = 400, description = "Recipe cannot be scaled to the requested servings", body = ApiError),
+        (status = 404, description = "No matching recipe", body = ApiError),
     )
 )]
 pub async fn get_recipe(
     State(app_state): State<Arc<RwLock<AppState>>>,
-    Path(recipe_id): Path<String>,
-) -> Result<response::Response, http::StatusCode> {
+    extract::Path(recipe_id): extract::Path<String>,
+    extract::Query(params): extract::Query<RecipeParams>,
+    request_headers: http::HeaderMap,
+) -> Result<response::Response, ApiError> {
+    let app_reader = app_state.read().await;
+    let db = &app_reader.db;
+    get_recipe_by_id(db, &recipe_id, &params, &Conditions::new(&request_headers)).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/recipes",
+    params(search::ListQuery),
+    responses(
+        (status = 200, description = "A page of recipes", body = search::RecipePage),
+        (status = 400, description = "Cursor does not belong to this listing", body = ApiError),
+    )
+)]
+pub async fn list_recipes(
+    State(app_state): State<Arc<RwLock<AppState>>>,
+    extract::Query(query): extract::Query<search::ListQuery>,
+) -> Result<response::Response, ApiError> {
     let app_reader = app_state.read().await;
     let db = &app_reader.db;
-    get_recipe_by_id(db, &recipe_id).await
+    match search::list(db, &query).await? {
+        Some(page) => Ok(Json(page).into_response()),
+        None => Err(ApiError::Validation("cursor does not belong to this listing".to_string())),
+    }
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct IngredientsParams {
+    /// Maximum number of recipes.
+    #[param(minimum = 1, maximum = 100, example = 20)]
+    limit: Option<i64>,
 }
 
+/// Reads its ingredients from a JSON body, which many clients and proxies
+/// cannot send with a GET; prefer `/recipes/search`.
 #[utoipa::path(
     get,
     path = "/recipe-by-ingredients",
+    params(IngredientsParams),
     responses(
-        (status = 200, description = "Get a recipe by ingredients", body = [JsonRecipe]),
-        (status = 404, description = "No matching recipes"),
+        (status = 200, description = "Recipes sharing ingredients, most shared first", body = [JsonRecipe]),
+        (status = 404, description = "No matching recipes", body = ApiError),
     )
 )]
 pub async fn get_recipe_by_ingredients(
     State(app_state): State<Arc<RwLock<AppState>>>,
-    Json(ingredients): Json<Vec<String>>,
-) -> Result<response::Response, http::StatusCode> {
+    extract::Query(params): extract::Query<IngredientsParams>,
+    extract::Json(ingredients): extract::Json<Vec<String>>,
+) -> Result<response::Response, ApiError> {
     log::info!("get recipe by ingredients: {:?}", ingredients);
     let app_reader = app_state.read().await;
     let db = &app_reader.db;
-    let recipe_result = recipe::get_by_ingredients(db, ingredients.iter().map(String::as_ref)).await;
-    match recipe_result {
-        Ok(Some(recipe_id)) => get_recipe_by_id(db, &recipe_id).await,
-        Ok(None) => {
-            log::warn!("recipe fetch by ingredients failed");
-            Err(http::StatusCode::NOT_FOUND)
-        }
-        Err(e) => {
-            log::warn!("recipe ingredients fetch failed: {}", e);
-            Err(http::StatusCode::NOT_FOUND)
-        }
+    let limit = params.limit.unwrap_or(20).clamp(1, 100);
+    let recipe_ids = recipe::get_by_ingredients(db, ingredients.iter().map(String::as_ref), limit).await?;
+    if recipe_ids.is_empty() {
+        return Err(ApiError::NotFound("no recipe uses these ingredients".to_string()));
     }
+    let recipes: Vec<JsonRecipe> = recipe::get_many(db, &recipe_ids)
+        .await?
+        .into_iter()
+        .map(|(recipe, ingredients)| JsonRecipe::new(recipe, ingredients))
+        .collect();
+    Ok(Json(recipes).into_response())
 }
 
 #[utoipa::path(
@@ -79,68 +209,816 @@ pub async fn get_recipe_by_ingredients(
     path = "/random-recipe",
     responses(
         (status = 200, description = "Get a random recipe", body = [JsonRecipe]),
-        (status = 404, description = "No recipe"),
+        (status = 404, description = "No recipe", body = ApiError),
     )
 )]
 pub async fn get_random_recipe(
     State(app_state): State<Arc<RwLock<AppState>>>,
-) -> Result<response::Response, http::StatusCode> {
+) -> Result<response::Response, ApiError> {
     let app_reader = app_state.read().await;
     let db = &app_reader.db;
-    let recipe_result = recipe::get_random(db).await;
-    match recipe_result {
-        Ok(recipe_id) => get_recipe_by_id(db, &recipe_id.to_string()).await,
-        Err(e) => {
-            log::warn!("get random recipe failed: {}", e);
-            Err(http::StatusCode::NOT_FOUND)
-        }
+    let recipe_id = match recipe::get_random(db).await {
+        Err(sqlx::Error::RowNotFound) => return Err(ApiError::NotFound("there are no recipes".to_string())),
+        result => result?,
+    };
+    get_recipe_by_id(db, &recipe_id.to_string(), &RecipeParams::default(), &Conditions::default()).await
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchParams {
+    /// Words to look for in titles, categories, preparations and ingredients.
+    #[param(example = "coffee cream")]
+    q: String,
+    /// Maximum number of results.
+    #[param(minimum = 1, maximum = 100, example = 20)]
+    limit: Option<i64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/search",
+    params(SearchParams),
+    responses(
+        (status = 200, description = "Recipes matching the query, best first", body = [search::SearchHit]),
+        (status = 400, description = "Empty query", body = ApiError),
+    )
+)]
+pub async fn search_recipes(
+    State(app_state): State<Arc<RwLock<AppState>>>,
+    extract::Query(params): extract::Query<SearchParams>,
+) -> Result<response::Response, ApiError> {
+    let app_reader = app_state.read().await;
+    let db = &app_reader.db;
+    let limit = params.limit.unwrap_or(20).clamp(1, 100);
+    match search::full_text(db, &params.q, limit).await? {
+        Some(hits) => Ok(Json(hits).into_response()),
+        None => Err(ApiError::Validation("query has no words to search for".to_string())),
     }
 }
 
+async fn find_recipes(db: &SqlitePool, query: &search::RecipeQuery) -> Result<response::Response, ApiError> {
+    match search::find(db, query).await? {
+        Some(recipes) => Ok(Json(recipes).into_response()),
+        None => Err(ApiError::Validation(
+            "give ingredients, a category or text to search for".to_string(),
+        )),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/recipes/search",
+    params(search::RecipeQueryParams),
+    responses(
+        (status = 200, description = "Matching recipes, best first", body = [JsonRecipe]),
+        (status = 400, description = "Nothing to search for", body = ApiError),
+    )
+)]
+pub async fn get_recipe_search(
+    State(app_state): State<Arc<RwLock<AppState>>>,
+    extract::Query(params): extract::Query<search::RecipeQueryParams>,
+) -> Result<response::Response, ApiError> {
+    let app_reader = app_state.read().await;
+    find_recipes(&app_reader.db, &params.into()).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/recipes/search",
+    request_body(
+        content = inline(search::RecipeQuery),
+        description = "What to look for"
+    ),
+    responses(
+        (status = 200, description = "Matching recipes, best first", body = [JsonRecipe]),
+        (status = 400, description = "Nothing to search for", body = ApiError),
+    )
+)]
+pub async fn post_recipe_search(
+    State(app_state): State<Arc<RwLock<AppState>>>,
+    extract::Json(query): extract::Json<search::RecipeQuery>,
+) -> Result<response::Response, ApiError> {
+    let app_reader = app_state.read().await;
+    find_recipes(&app_reader.db, &query).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/recipes-by-pantry",
+    request_body(
+        content = inline(search::PantryQuery),
+        description = "Ingredients on hand"
+    ),
+    responses(
+        (status = 200, description = "Recipes ranked by pantry coverage", body = [search::PantryMatch]),
+    )
+)]
+pub async fn get_recipes_by_pantry(
+    State(app_state): State<Arc<RwLock<AppState>>>,
+    extract::Json(query): extract::Json<search::PantryQuery>,
+) -> Result<response::Response, ApiError> {
+    let app_reader = app_state.read().await;
+    let db = &app_reader.db;
+    let matches = search::pantry(db, &query).await?;
+    Ok(Json(matches).into_response())
+}
+
 #[utoipa::path(
     post,
     path = "/register",
     request_body(
         content = inline(authjwt::Registration),
-        description = "Get an API key",
+        description = "Create an account",
     ),
     responses(
-        (status = 200, description = "JSON Web Token", body = authjwt::AuthBody),
-        (status = 401, description = "Registration failed", body = authjwt::AuthError),
+        (status = 201, description = "JSON Web Token for the new account", body = authjwt::AuthBody),
+        (status = 400, description = "Missing name, invalid email or short password", body = ApiError),
+        (status = 409, description = "Email already registered", body = ApiError),
+        (status = 429, description = "Too many failed attempts; see Retry-After", body = ApiError),
     )
 )]
 pub async fn register(
     State(appstate): State<SharedAppState>,
-    Json(registration): Json<authjwt::Registration>,
+    throttle::ClientIp(ip): throttle::ClientIp,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Json(registration): extract::Json<authjwt::Registration>,
+) -> axum::response::Response {
+    let appstate = appstate.read().await;
+    match authjwt::register(&appstate, &registration, ip, request_id.as_deref()).await {
+        Err(e) => e.into_response(),
+        Ok(token) => (StatusCode::CREATED, token).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/login",
+    request_body(
+        content = inline(authjwt::Login),
+        description = "Sign in to an account",
+    ),
+    responses(
+        (status = 200, description = "JSON Web Token", body = authjwt::AuthBody),
+        (status = 401, description = "Wrong credentials, or authenticator code required", body = ApiError),
+        (status = 429, description = "Too many failed attempts; see Retry-After", body = ApiError),
+    )
+)]
+pub async fn login(
+    State(appstate): State<SharedAppState>,
+    throttle::ClientIp(ip): throttle::ClientIp,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Json(login): extract::Json<authjwt::Login>,
 ) -> axum::response::Response {
     let appstate = appstate.read().await;
-    match authjwt::make_jwt_token(&appstate, &registration) {
+    match authjwt::login(&appstate, &login, ip, request_id.as_deref()).await {
         Err(e) => e.into_response(),
         Ok(token) => (StatusCode::OK, token).into_response(),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/oidc/login",
+    responses(
+        (status = 303, description = "Redirect to the identity provider to sign in"),
+        (status = 404, description = "Identity provider login not configured", body = ApiError),
+    )
+)]
+pub async fn oidc_login(State(appstate): State<SharedAppState>, jar: CookieJar) -> axum::response::Response {
+    let appstate = appstate.read().await;
+    let Some(provider) = &appstate.oidc else {
+        return authjwt::AuthError::OidcDisabled.into_response();
+    };
+    match provider.start_login(&appstate.db, jar, None).await {
+        Err(e) => e.into_response(),
+        Ok((url, jar)) => (jar, response::Redirect::to(&url)).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/oidc/callback",
+    params(oidc::Callback),
+    responses(
+        (status = 200, description = "JSON Web Token for the signed in account", body = authjwt::AuthBody),
+        (status = 401, description = "Login refused, expired, or not started in this browser", body = ApiError),
+        (status = 403, description = "Account has an authenticator and the identity provider is not trusted to ask for a second factor", body = ApiError),
+        (status = 404, description = "Identity provider login not configured", body = ApiError),
+        (status = 409, description = "Email registered to a password account, which must link the identity itself; \
+            or identity linked to another account", body = ApiError),
+        (status = 502, description = "Identity provider unreachable or misbehaving", body = ApiError),
+    )
+)]
+pub async fn oidc_callback(
+    State(appstate): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    jar: CookieJar,
+    extract::Query(callback): extract::Query<oidc::Callback>,
+) -> axum::response::Response {
+    let appstate = appstate.read().await;
+    let Some(provider) = &appstate.oidc else {
+        return authjwt::AuthError::OidcDisabled.into_response();
+    };
+    let logged_in = oidc::login(&appstate, provider, &jar, &callback, request_id.as_deref()).await;
+    let jar = oidc::end_login(provider, jar);
+    match logged_in {
+        Err(e) => (jar, e).into_response(),
+        Ok(token) => (StatusCode::OK, jar, token).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/oidc/link",
+    responses(
+        (status = 200, description = "Identity provider URL to send this browser to; \
+            the identity it signs in as is linked to the caller's account", body = oidc::LinkStart),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Not allowed with an API key", body = ApiError),
+        (status = 404, description = "Identity provider login not configured", body = ApiError),
+    )
+)]
+pub async fn oidc_link(
+    claims: authjwt::Claims,
+    State(appstate): State<SharedAppState>,
+    jar: CookieJar,
+) -> Result<response::Response, ApiError> {
+    let appstate = appstate.read().await;
+    let provider = appstate.oidc.as_ref().ok_or(authjwt::AuthError::OidcDisabled)?;
+    let (link, jar) = oidc::start_link(&appstate, provider, jar, &claims).await?;
+    Ok((jar, Json(link)).into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/token/refresh",
+    request_body(
+        content = inline(authjwt::RefreshRequest),
+        description = "Refresh token from the last login or refresh",
+    ),
+    responses(
+        (status = 200, description = "New access and refresh tokens", body = authjwt::AuthBody),
+        (status = 401, description = "Unknown, expired or already used refresh token", body = ApiError),
+        (status = 429, description = "Too many failed attempts; see Retry-After", body = ApiError),
+    )
+)]
+pub async fn refresh_token(
+    State(appstate): State<SharedAppState>,
+    throttle::ClientIp(ip): throttle::ClientIp,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Json(request): extract::Json<authjwt::RefreshRequest>,
+) -> axum::response::Response {
+    let appstate = appstate.read().await;
+    match authjwt::refresh(&appstate, &request, ip, request_id.as_deref()).await {
+        Err(e) => e.into_response(),
+        Ok(token) => (StatusCode::OK, token).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/logout",
+    request_body(
+        content = inline(authjwt::RefreshRequest),
+        description = "Refresh token to revoke along with the access token",
+    ),
+    responses(
+        (status = 204, description = "Tokens revoked"),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Not allowed with an API key", body = ApiError),
+    )
+)]
+pub async fn logout(
+    claims: authjwt::Claims,
+    State(appstate): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Json(request): extract::Json<authjwt::RefreshRequest>,
+) -> axum::response::Response {
+    let appstate = appstate.read().await;
+    match authjwt::logout(&appstate, &claims, &request, request_id.as_deref()).await {
+        Err(e) => e.into_response(),
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
 #[utoipa::path(
     post,
     path = "/add-recipe",
     request_body(
         content = inline(JsonRecipe),
-        description = "Recipe to add"
+        description = "Recipe to add; leave out `id` to have one assigned"
     ),
     responses(
-        (status = 201, description = "Added recipe", body = ()),
-        (status = 400, description = "Bad request", body = String),
-        (status = 401, description = "Auth Error", body = authjwt::AuthError),
+        (status = 201, description = "Added recipe", body = JsonRecipe,
+            headers(("Location" = String, description = "URL of the new recipe"))),
+        (status = 400, description = "Bad request", body = ApiError),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Role not allowed", body = ApiError),
+        (status = 409, description = "A recipe with this id already exists", body = ApiError),
     )
 )]
 pub async fn add_recipe(
-    _claims: authjwt::Claims,
+    auth: authjwt::Authorized<authjwt::role::Contributor>,
+    State(appstate): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Json(recipe): extract::Json<JsonRecipe>,
+) -> Result<response::Response, ApiError> {
+    let author_id = auth.claims.user_id()?;
+    let appstate = appstate.read().await;
+    let mut tx = appstate.db.begin().await?;
+    let recipe_id = match recipe::add(&mut tx, recipe, Some(author_id)).await {
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Err(ApiError::Conflict("a recipe with this id already exists".to_string()));
+        }
+        result => result?,
+    };
+    let action = audit::Action::RecipeCreate;
+    audit_recipe(&mut tx, action, &auth.claims, recipe_id, request_id.as_deref(), None).await?;
+    tx.commit().await?;
+    let (recipe, ingredients) = recipe::get(&appstate.db, &recipe_id.to_string()).await?;
+    let location = format!("/api/v1/recipe/{}", recipe_id);
+    let recipe = JsonRecipe::new(recipe, ingredients);
+    Ok((StatusCode::CREATED, [(http::header::LOCATION, location)], Json(recipe)).into_response())
+}
+
+/// Record a recipe change by the caller with the fields it changed, in the
+/// transaction `conn` that made it.
+async fn audit_recipe(
+    conn: &mut sqlx::SqliteConnection,
+    action: audit::Action,
+    claims: &authjwt::Claims,
+    recipe_id: i64,
+    request_id: Option<&str>,
+    before: Option<serde_json::Value>,
+) -> Result<(), sqlx::Error> {
+    let after = match action {
+        audit::Action::RecipeDelete => None,
+        _ => recipe::snapshot(&mut *conn, recipe_id).await,
+    };
+    let event = audit::Event::new(action, request_id)
+        .actor(claims.user_id().ok())
+        .target(recipe_id)
+        .diff(audit::diff(before.as_ref(), after.as_ref()));
+    audit::write(conn, event).await
+}
+
+/// Stop callers other than the recipe's author or an editor from changing it.
+async fn check_can_modify(
+    db: &SqlitePool,
+    claims: &authjwt::Claims,
+    recipe_id: i64,
+) -> Result<(), ApiError> {
+    match recipe::author_id(db, recipe_id).await? {
+        None => Err(recipe_not_found(recipe_id)),
+        Some(author_id) if claims.can_modify(author_id) => Ok(()),
+        Some(_) => Err(authjwt::AuthError::NotAuthor.into()),
+    }
+}
+
+fn recipe_not_found(recipe_id: i64) -> ApiError {
+    ApiError::NotFound(format!("no recipe with id {}", recipe_id))
+}
+
+/// The version of a recipe a change applies to: the current one, provided
+/// the change's `If-Match` names it.
+async fn check_if_match(
+    db: &SqlitePool,
+    recipe_id: i64,
+    request_headers: &http::HeaderMap,
+) -> Result<i64, ApiError> {
+    if !request_headers.contains_key(http::header::IF_MATCH) {
+        return Err(ApiError::PreconditionRequired(
+            "send the recipe's ETag in If-Match".to_string(),
+        ));
+    }
+    let (version, current) = recipe::version(db, recipe_id).await?.ok_or_else(|| recipe_not_found(recipe_id))?;
+    let if_match = request_headers.typed_get::<headers::IfMatch>();
+    if !if_match.is_some_and(|if_match| if_match.precondition_passes(&current)) {
+        return Err(recipe_changed(recipe_id));
+    }
+    Ok(version)
+}
+
+fn recipe_changed(recipe_id: i64) -> ApiError {
+    ApiError::PreconditionFailed(format!("recipe {} has changed since it was fetched", recipe_id))
+}
+
+#[utoipa::path(
+    put,
+    path = "/recipe/{recipe_id}",
+    request_body(
+        content = inline(JsonRecipe),
+        description = "Recipe to store in place of the current one"
+    ),
+    params(
+        ("If-Match" = String, Header, description = "ETag of the recipe as last fetched"),
+    ),
+    responses(
+        (status = 200, description = "Updated recipe", body = JsonRecipe,
+            headers(("ETag" = String, description = "Tag of the recipe's new version"))),
+        (status = 400, description = "Bad request, or a body id other than the path's", body = ApiError),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Neither the recipe's author nor an editor", body = ApiError),
+        (status = 404, description = "No matching recipe", body = ApiError),
+        (status = 412, description = "The recipe has changed since it was fetched", body = ApiError),
+        (status = 428, description = "No If-Match header", body = ApiError),
+    )
+)]
+pub async fn update_recipe(
+    auth: authjwt::Authorized<authjwt::role::Contributor>,
+    State(appstate): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Path(recipe_id): extract::Path<i64>,
+    request_headers: http::HeaderMap,
+    extract::Json(recipe): extract::Json<JsonRecipe>,
+) -> Result<response::Response, ApiError> {
+    if let Some(id) = recipe.id()
+        && id != recipe_id
+    {
+        return Err(ApiError::Validation(format!(
+            "recipe id {} does not match path id {}",
+            id, recipe_id
+        )));
+    }
+    let appstate = appstate.read().await;
+    check_can_modify(&appstate.db, &auth.claims, recipe_id).await?;
+    let version = check_if_match(&appstate.db, recipe_id, &request_headers).await?;
+    let mut tx = appstate.db.begin().await?;
+    let before = recipe::snapshot(&mut tx, recipe_id).await;
+    if !recipe::update(&mut tx, recipe_id, version, recipe).await? {
+        return Err(recipe_changed(recipe_id));
+    }
+    let action = audit::Action::RecipeUpdate;
+    audit_recipe(&mut tx, action, &auth.claims, recipe_id, request_id.as_deref(), before).await?;
+    tx.commit().await?;
+    let params = RecipeParams::default();
+    get_recipe_by_id(&appstate.db, &recipe_id.to_string(), &params, &Conditions::default()).await
+}
+
+#[utoipa::path(
+    patch,
+    path = "/recipe/{recipe_id}",
+    request_body(
+        content = inline(RecipePatch),
+        description = "Recipe fields to change"
+    ),
+    params(
+        ("If-Match" = String, Header, description = "ETag of the recipe as last fetched"),
+    ),
+    responses(
+        (status = 200, description = "Updated recipe", body = JsonRecipe,
+            headers(("ETag" = String, description = "Tag of the recipe's new version"))),
+        (status = 400, description = "Bad request", body = ApiError),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Neither the recipe's author nor an editor", body = ApiError),
+        (status = 404, description = "No matching recipe", body = ApiError),
+        (status = 412, description = "The recipe has changed since it was fetched", body = ApiError),
+        (status = 428, description = "No If-Match header", body = ApiError),
+    )
+)]
+pub async fn patch_recipe(
+    auth: authjwt::Authorized<authjwt::role::Contributor>,
+    State(appstate): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Path(recipe_id): extract::Path<i64>,
+    request_headers: http::HeaderMap,
+    extract::Json(patch): extract::Json<RecipePatch>,
+) -> Result<response::Response, ApiError> {
+    let appstate = appstate.read().await;
+    check_can_modify(&appstate.db, &auth.claims, recipe_id).await?;
+    let version = check_if_match(&appstate.db, recipe_id, &request_headers).await?;
+    let mut tx = appstate.db.begin().await?;
+    let before = recipe::snapshot(&mut tx, recipe_id).await;
+    if !recipe::patch(&mut tx, recipe_id, version, patch).await? {
+        return Err(recipe_changed(recipe_id));
+    }
+    let action = audit::Action::RecipeUpdate;
+    audit_recipe(&mut tx, action, &auth.claims, recipe_id, request_id.as_deref(), before).await?;
+    tx.commit().await?;
+    let params = RecipeParams::default();
+    get_recipe_by_id(&appstate.db, &recipe_id.to_string(), &params, &Conditions::default()).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/recipe/{recipe_id}",
+    responses(
+        (status = 204, description = "Deleted recipe"),
+        (status = 400, description = "Bad request", body = ApiError),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Neither the recipe's author nor an editor", body = ApiError),
+        (status = 404, description = "No matching recipe", body = ApiError),
+    )
+)]
+pub async fn delete_recipe(
+    auth: authjwt::Authorized<authjwt::role::Contributor>,
+    State(appstate): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Path(recipe_id): extract::Path<i64>,
+) -> Result<response::Response, ApiError> {
+    let appstate = appstate.read().await;
+    check_can_modify(&appstate.db, &auth.claims, recipe_id).await?;
+    let mut tx = appstate.db.begin().await?;
+    let before = recipe::snapshot(&mut tx, recipe_id).await;
+    if !recipe::delete(&mut tx, recipe_id).await? {
+        return Err(recipe_not_found(recipe_id));
+    }
+    let action = audit::Action::RecipeDelete;
+    audit_recipe(&mut tx, action, &auth.claims, recipe_id, request_id.as_deref(), before).await?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RoleChange {
+    #[schema(example = "editor")]
+    role: user::Role,
+}
+
+#[utoipa::path(
+    put,
+    path = "/users/{user_id}/role",
+    request_body(
+        content = inline(RoleChange),
+        description = "Role to give the account; it applies to tokens issued from now on"
+    ),
+    responses(
+        (status = 204, description = "Role changed"),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Role not allowed", body = ApiError),
+        (status = 404, description = "No matching user", body = ApiError),
+        (status = 409, description = "The account is the last admin", body = ApiError),
+    )
+)]
+pub async fn set_user_role(
+    auth: authjwt::Authorized<authjwt::role::Admin>,
+    State(appstate): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Path(user_id): extract::Path<i64>,
+    extract::Json(change): extract::Json<RoleChange>,
+) -> Result<response::Response, ApiError> {
+    log::info!("user {} sets role of user {} to {:?}", auth.claims.sub(), user_id, change.role);
+    let appstate = appstate.read().await;
+    let user_not_found = || ApiError::NotFound(format!("no user with id {}", user_id));
+    let mut tx = appstate.db.begin().await?;
+    let before = user::get_role(&mut *tx, user_id).await?.ok_or_else(user_not_found)?;
+    if !user::set_role(&mut *tx, user_id, change.role).await? {
+        return Err(ApiError::Conflict("the last admin cannot be demoted".to_string()));
+    }
+    let event = audit::Event::new(audit::Action::RoleChange, request_id.as_deref())
+        .actor(auth.claims.user_id().ok())
+        .target(user_id)
+        .diff(serde_json::json!({ "role": { "before": before, "after": change.role } }));
+    audit::write(&mut *tx, event).await?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api-keys",
+    request_body(
+        content = inline(authjwt::NewApiKey),
+        description = "Name and scope of the key"
+    ),
+    responses(
+        (status = 201, description = "New API key; send it as a Bearer token", body = authjwt::CreatedApiKey),
+        (status = 400, description = "Missing name", body = ApiError),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Not allowed with an API key", body = ApiError),
+    )
+)]
+pub async fn create_api_key(
+    claims: authjwt::Claims,
+    State(appstate): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Json(new_key): extract::Json<authjwt::NewApiKey>,
+) -> axum::response::Response {
+    let appstate = appstate.read().await;
+    match authjwt::create_api_key(&appstate, &claims, &new_key, request_id.as_deref()).await {
+        Err(e) => e.into_response(),
+        Ok(created) => (StatusCode::CREATED, Json(created)).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api-keys",
+    responses(
+        (status = 200, description = "The caller's API keys", body = [apikey::ApiKeyInfo]),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Not allowed with an API key", body = ApiError),
+    )
+)]
+pub async fn list_api_keys(
+    claims: authjwt::Claims,
+    State(appstate): State<SharedAppState>,
+) -> Result<response::Response, ApiError> {
+    claims.require_session()?;
+    let user_id = claims.user_id()?;
+    let appstate = appstate.read().await;
+    let keys = apikey::list(&appstate.db, user_id).await?;
+    Ok(Json(keys).into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api-keys/{key_id}",
+    responses(
+        (status = 204, description = "API key revoked"),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Not allowed with an API key", body = ApiError),
+        (status = 404, description = "No matching live key of the caller", body = ApiError),
+    )
+)]
+pub async fn revoke_api_key(
+    claims: authjwt::Claims,
+    State(appstate): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Path(key_id): extract::Path<i64>,
+) -> Result<response::Response, ApiError> {
+    claims.require_session()?;
+    let user_id = claims.user_id()?;
+    let appstate = appstate.read().await;
+    let mut tx = appstate.db.begin().await?;
+    if !apikey::revoke(&mut *tx, user_id, key_id, Utc::now().timestamp()).await? {
+        return Err(ApiError::NotFound(format!("you have no live API key with id {}", key_id)));
+    }
+    let event = audit::Event::new(audit::Action::ApiKeyRevoke, request_id.as_deref())
+        .actor(Some(user_id))
+        .target(key_id);
+    audit::write(&mut *tx, event).await?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/audit",
+    params(audit::AuditQuery),
+    responses(
+        (status = 200, description = "Audit log entries, newest first", body = [audit::AuditEntry]),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Role not allowed", body = ApiError),
+    )
+)]
+pub async fn get_audit_log(
+    _auth: authjwt::Authorized<authjwt::role::Admin>,
+    State(appstate): State<SharedAppState>,
+    extract::Query(query): extract::Query<audit::AuditQuery>,
+) -> Result<response::Response, ApiError> {
+    let appstate = appstate.read().await;
+    let entries = audit::list(&appstate.db, &query).await?;
+    Ok(Json(entries).into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/totp/enroll",
+    responses(
+        (status = 200, description = "Secret to add to an authenticator app", body = totp::Enrollment),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Role not allowed, or called with an API key", body = ApiError),
+        (status = 409, description = "Authenticator already enabled", body = ApiError),
+    )
+)]
+pub async fn enroll_totp(
+    auth: authjwt::Authorized<authjwt::role::Editor>,
     State(appstate): State<SharedAppState>,
-    Json(recipe): Json<JsonRecipe>,
 ) -> axum::response::Response {
     let appstate = appstate.read().await;
-    match recipe::add(&appstate.db, recipe).await {
-        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
-        Ok(()) => StatusCode::CREATED.into_response(),
+    match totp::enroll(&appstate, &auth.claims).await {
+        Err(e) => e.into_response(),
+        Ok(enrollment) => Json(enrollment).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/totp/confirm",
+    request_body(
+        content = inline(totp::Confirmation),
+        description = "First code from the authenticator app",
+    ),
+    responses(
+        (status = 200, description = "Two-factor login enabled; recovery codes", body = totp::RecoveryCodes),
+        (status = 400, description = "Wrong code", body = ApiError),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Role not allowed, or called with an API key", body = ApiError),
+        (status = 404, description = "No enrollment started", body = ApiError),
+        (status = 409, description = "Authenticator already enabled", body = ApiError),
+        (status = 429, description = "Too many wrong codes; see Retry-After", body = ApiError),
+    )
+)]
+pub async fn confirm_totp(
+    auth: authjwt::Authorized<authjwt::role::Editor>,
+    State(appstate): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Json(confirmation): extract::Json<totp::Confirmation>,
+) -> axum::response::Response {
+    let appstate = appstate.read().await;
+    match totp::confirm(&appstate, &auth.claims, &confirmation, request_id.as_deref()).await {
+        Err(e) => e.into_response(),
+        Ok(codes) => Json(codes).into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/totp",
+    request_body(
+        content = inline(totp::Confirmation),
+        description = "Current code from the authenticator app",
+    ),
+    responses(
+        (status = 204, description = "Two-factor login disabled; recovery codes discarded"),
+        (status = 400, description = "Wrong code", body = ApiError),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Not allowed with an API key", body = ApiError),
+        (status = 404, description = "Authenticator not enabled", body = ApiError),
+        (status = 429, description = "Too many wrong codes; see Retry-After", body = ApiError),
+    )
+)]
+pub async fn disable_totp(
+    claims: authjwt::Claims,
+    State(appstate): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Json(confirmation): extract::Json<totp::Confirmation>,
+) -> axum::response::Response {
+    let appstate = appstate.read().await;
+    match totp::disable(&appstate, &claims, &confirmation, request_id.as_deref()).await {
+        Err(e) => e.into_response(),
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/{user_id}/totp",
+    responses(
+        (status = 204, description = "Authenticator and recovery codes removed; the account signs in with its password alone"),
+        (status = 401, description = "Auth Error", body = ApiError),
+        (status = 403, description = "Role not allowed", body = ApiError),
+        (status = 404, description = "No authenticator on the account", body = ApiError),
+    )
+)]
+pub async fn reset_user_totp(
+    auth: authjwt::Authorized<authjwt::role::Admin>,
+    State(appstate): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    extract::Path(user_id): extract::Path<i64>,
+) -> Result<response::Response, ApiError> {
+    log::info!("user {} resets the authenticator of user {}", auth.claims.sub(), user_id);
+    let appstate = appstate.read().await;
+    let mut tx = appstate.db.begin().await?;
+    if !totp::remove(&mut tx, user_id).await? {
+        return Err(ApiError::NotFound(format!("user {} has no authenticator", user_id)));
+    }
+    let event = audit::Event::new(audit::Action::TotpDisable, request_id.as_deref())
+        .actor(auth.claims.user_id().ok())
+        .target(user_id);
+    audit::write(&mut *tx, event).await?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conditions(pairs: &[(http::HeaderName, &str)]) -> Conditions {
+        let mut request_headers = http::HeaderMap::new();
+        for (name, value) in pairs {
+            request_headers.insert(name, value.parse().unwrap());
+        }
+        Conditions::new(&request_headers)
+    }
+
+    fn if_none_match(etag: headers::ETag) -> Conditions {
+        Conditions { if_none_match: Some(etag.into()) }
+    }
+
+    #[test]
+    fn not_modified_without_conditions_is_false() {
+        let etag = recipe::etag(7, 2, Some("Ann"));
+        assert!(!conditions(&[]).not_modified(&etag));
+    }
+
+    #[test]
+    fn not_modified_matches_etag() {
+        let etag = recipe::etag(7, 2, Some("Ann"));
+        assert!(if_none_match(etag.clone()).not_modified(&etag));
+        assert!(conditions(&[(http::header::IF_NONE_MATCH, "*")]).not_modified(&etag));
+        assert!(!if_none_match(recipe::etag(7, 1, Some("Ann"))).not_modified(&etag));
+        assert!(!if_none_match(recipe::etag(7, 2, Some("Bob"))).not_modified(&etag));
+    }
+
+    #[test]
+    fn not_modified_ignores_if_modified_since() {
+        // The author may have been renamed since, which only the ETag shows.
+        let etag = recipe::etag(7, 2, Some("Bob"));
+        let since = conditions(&[(http::header::IF_MODIFIED_SINCE, "Fri, 01 Jan 2100 00:00:00 GMT")]);
+        assert!(!since.not_modified(&etag));
     }
 }
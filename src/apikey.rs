@@ -0,0 +1,119 @@
+use crate::*;
+
+/// What an API key may be used for. A read key acts as a reader whatever
+/// its owner's role; a write key acts with the owner's role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+}
+
+/// An API key as shown to its owner; the key itself is only shown once,
+/// when it is created.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiKeyInfo {
+    #[schema(example = 3)]
+    id: i64,
+    #[schema(example = "nightly import")]
+    name: String,
+    scope: Scope,
+    /// Start of the key.
+    #[schema(example = "rsk_Jx3q")]
+    prefix: String,
+    /// Unix seconds.
+    #[schema(example = 1760786400)]
+    created_at: i64,
+    #[schema(example = 1760790000)]
+    last_used_at: Option<i64>,
+    revoked: bool,
+}
+
+/// The account an API key acts for.
+pub struct KeyOwner {
+    pub key_id: i64,
+    pub user_id: i64,
+    pub scope: Scope,
+    pub role: user::Role,
+}
+
+pub async fn add(
+    db: impl sqlx::SqliteExecutor<'_>,
+    user_id: i64,
+    name: &str,
+    prefix: &str,
+    key_hash: &str,
+    scope: Scope,
+    now: i64,
+) -> Result<i64, sqlx::Error> {
+    let inserted = sqlx::query!(
+        r#"INSERT INTO api_keys (user_id, name, prefix, key_hash, scope, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6);"#,
+        user_id,
+        name,
+        prefix,
+        key_hash,
+        scope,
+        now,
+    )
+    .execute(db)
+    .await?;
+    Ok(inserted.last_insert_rowid())
+}
+
+pub async fn get(db: impl sqlx::SqliteExecutor<'_>, user_id: i64, key_id: i64) -> Result<Option<ApiKeyInfo>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKeyInfo,
+        r#"SELECT id, name, scope AS "scope: Scope", prefix, created_at, last_used_at,
+          revoked_at IS NOT NULL AS "revoked!: bool"
+        FROM api_keys WHERE user_id = $1 AND id = $2;"#,
+        user_id,
+        key_id,
+    )
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn list(db: &SqlitePool, user_id: i64) -> Result<Vec<ApiKeyInfo>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKeyInfo,
+        r#"SELECT id, name, scope AS "scope: Scope", prefix, created_at, last_used_at,
+          revoked_at IS NOT NULL AS "revoked!: bool"
+        FROM api_keys WHERE user_id = $1
+        ORDER BY id;"#,
+        user_id,
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Revoke one of `user_id`'s keys. Returns `false` if they have no such
+/// live key.
+pub async fn revoke(db: impl sqlx::SqliteExecutor<'_>, user_id: i64, key_id: i64, now: i64) -> Result<bool, sqlx::Error> {
+    let revoked = sqlx::query!(
+        r#"UPDATE api_keys SET revoked_at = $1
+        WHERE user_id = $2 AND id = $3 AND revoked_at IS NULL;"#,
+        now,
+        user_id,
+        key_id,
+    )
+    .execute(db)
+    .await?;
+    Ok(revoked.rows_affected() > 0)
+}
+
+/// Owner of the live key with hash `key_hash`, noting that it was used.
+pub async fn authenticate(db: &SqlitePool, key_hash: &str, now: i64) -> Result<Option<KeyOwner>, sqlx::Error> {
+    sqlx::query_as!(
+        KeyOwner,
+        r#"UPDATE api_keys SET last_used_at = $1
+        WHERE key_hash = $2 AND revoked_at IS NULL
+        RETURNING id AS key_id, user_id, scope AS "scope: Scope",
+          (SELECT role FROM users WHERE users.id = api_keys.user_id) AS "role!: user::Role";"#,
+        now,
+        key_hash,
+    )
+    .fetch_optional(db)
+    .await
+}
@@ -0,0 +1,242 @@
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Action {
+    RecipeCreate,
+    RecipeUpdate,
+    RecipeDelete,
+    Register,
+    Login,
+    LoginFailed,
+    TokenRefresh,
+    Logout,
+    RoleChange,
+    ApiKeyCreate,
+    ApiKeyRevoke,
+    TotpEnable,
+    TotpDisable,
+}
+
+/// Something to write to the audit log.
+pub struct Event<'a> {
+    pub action: Action,
+    pub actor_id: Option<i64>,
+    /// The recipe, user or API key acted on.
+    pub target_id: Option<i64>,
+    pub request_id: Option<&'a str>,
+    pub diff: Option<serde_json::Value>,
+}
+
+impl<'a> Event<'a> {
+    pub fn new(action: Action, request_id: Option<&'a str>) -> Self {
+        Self {
+            action,
+            actor_id: None,
+            target_id: None,
+            request_id,
+            diff: None,
+        }
+    }
+
+    pub fn actor(mut self, actor_id: Option<i64>) -> Self {
+        self.actor_id = actor_id;
+        self
+    }
+
+    pub fn target(mut self, target_id: i64) -> Self {
+        self.target_id = Some(target_id);
+        self
+    }
+
+    pub fn diff(mut self, diff: serde_json::Value) -> Self {
+        self.diff = Some(diff);
+        self
+    }
+}
+
+/// The `x-request-id` of the request, as set by the request id layer.
+pub struct RequestId(pub Option<String>);
+
+/// Drop any `x-request-id` the client sent, so that the request id layer
+/// always makes a fresh one and a client cannot pick the id its events are
+/// logged under. Goes outside the request id layer.
+pub async fn drop_client_request_id(mut request: axum::extract::Request) -> axum::extract::Request {
+    request.headers_mut().remove("x-request-id");
+    request
+}
+
+impl<S: Send + Sync> axum::extract::FromRequestParts<S> for RequestId {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut http::request::Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let request_id = parts
+            .headers
+            .get("x-request-id")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        Ok(Self(request_id))
+    }
+}
+
+/// Fields whose values differ between two JSON objects, as
+/// `{"field": {"before": ..., "after": ...}}`. A missing side counts as
+/// null, so a created or deleted object shows every field. `updated_at` is
+/// left out since it changes on every update.
+pub fn diff(before: Option<&serde_json::Value>, after: Option<&serde_json::Value>) -> serde_json::Value {
+    let empty = serde_json::Map::new();
+    let before = before.and_then(|v| v.as_object()).unwrap_or(&empty);
+    let after = after.and_then(|v| v.as_object()).unwrap_or(&empty);
+    let mut changes = serde_json::Map::new();
+    for key in before.keys().chain(after.keys()) {
+        if key == "updated_at" || changes.contains_key(key) {
+            continue;
+        }
+        let old = before.get(key).unwrap_or(&serde_json::Value::Null);
+        let new = after.get(key).unwrap_or(&serde_json::Value::Null);
+        if old != new {
+            changes.insert(key.clone(), serde_json::json!({ "before": old, "after": new }));
+        }
+    }
+    serde_json::Value::Object(changes)
+}
+
+/// Append `event` to the audit log. Pass the transaction making the change
+/// it describes, so that neither is stored without the other.
+pub async fn write(db: impl sqlx::SqliteExecutor<'_>, event: Event<'_>) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    let diff = event.diff.map(|diff| diff.to_string());
+    sqlx::query!(
+        r#"INSERT INTO audit_log (at, actor_id, action, target_id, request_id, diff)
+        VALUES ($1, $2, $3, $4, $5, $6);"#,
+        now,
+        event.actor_id,
+        event.action,
+        event.target_id,
+        event.request_id,
+        diff,
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Append `event` for a sign-in or token use, which has no change of its
+/// own to roll back. A failure is logged rather than returned.
+pub async fn record(db: &SqlitePool, event: Event<'_>) {
+    let (action, actor_id) = (event.action, event.actor_id);
+    if let Err(e) = write(db, event).await {
+        log::warn!("audit record failed: {:?} by {:?}: {}", action, actor_id, e);
+    }
+}
+
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Only events by this user.
+    #[param(example = 1)]
+    actor_id: Option<i64>,
+    action: Option<Action>,
+    /// Only events on this recipe, user or API key id; best combined with
+    /// `action`.
+    #[param(example = 2)]
+    target_id: Option<i64>,
+    #[param(example = "0193a6f2-6b1e-7c3a-9a55-0c6f1b9e2d41")]
+    request_id: Option<String>,
+    /// Only events at or after this time.
+    #[param(value_type = Option<String>, format = DateTime)]
+    since: Option<DateTime<Utc>>,
+    /// Only events before this time.
+    #[param(value_type = Option<String>, format = DateTime)]
+    until: Option<DateTime<Utc>>,
+    /// Only events older than this entry id, for paging back.
+    before_id: Option<i64>,
+    #[param(minimum = 1, maximum = 500, example = 50)]
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AuditEntry {
+    #[schema(example = 41)]
+    id: i64,
+    #[schema(value_type = String, format = DateTime)]
+    at: DateTime<Utc>,
+    #[schema(example = 1)]
+    actor_id: Option<i64>,
+    action: Action,
+    #[schema(example = 2)]
+    target_id: Option<i64>,
+    request_id: Option<String>,
+    #[schema(value_type = Option<Object>,
+        example = json!({"title": {"before": "Pastry Cream", "after": "Crème Pâtissière"}}))]
+    diff: Option<serde_json::Value>,
+}
+
+/// Entries matching `query`, newest first.
+pub async fn list(db: &SqlitePool, query: &AuditQuery) -> Result<Vec<AuditEntry>, sqlx::Error> {
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    let rows = sqlx::query!(
+        r#"SELECT id, at AS "at: DateTime<Utc>", actor_id, action AS "action: Action", target_id,
+          request_id, diff
+        FROM audit_log
+        WHERE ($1 IS NULL OR actor_id = $1)
+          AND ($2 IS NULL OR action = $2)
+          AND ($3 IS NULL OR target_id = $3)
+          AND ($4 IS NULL OR request_id = $4)
+          AND ($5 IS NULL OR at >= $5)
+          AND ($6 IS NULL OR at < $6)
+          AND ($7 IS NULL OR id < $7)
+        ORDER BY id DESC
+        LIMIT $8;"#,
+        query.actor_id,
+        query.action,
+        query.target_id,
+        query.request_id,
+        query.since,
+        query.until,
+        query.before_id,
+        limit,
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| AuditEntry {
+            id: row.id,
+            at: row.at,
+            actor_id: row.actor_id,
+            action: row.action,
+            target_id: row.target_id,
+            request_id: row.request_id,
+            diff: row.diff.and_then(|diff| serde_json::from_str(&diff).ok()),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn diff_lists_changed_fields_only() {
+        let before = json!({ "title": "Pastry Cream", "servings": 4, "updated_at": "2025-01-01" });
+        let after = json!({ "title": "Crème Pâtissière", "servings": 4, "updated_at": "2025-02-01" });
+        assert_eq!(
+            diff(Some(&before), Some(&after)),
+            json!({ "title": { "before": "Pastry Cream", "after": "Crème Pâtissière" } })
+        );
+        assert_eq!(diff(Some(&before), Some(&before)), json!({}));
+    }
+
+    #[test]
+    fn diff_treats_missing_side_as_null() {
+        let recipe = json!({ "title": "Coffee Cream", "servings": null });
+        assert_eq!(diff(None, Some(&recipe)), json!({ "title": { "before": null, "after": "Coffee Cream" } }));
+        assert_eq!(diff(Some(&recipe), None), json!({ "title": { "before": "Coffee Cream", "after": null } }));
+        let added = json!({ "title": "Coffee Cream", "servings": null, "author": "Jo" });
+        assert_eq!(diff(Some(&recipe), Some(&added)), json!({ "author": { "before": null, "after": "Jo" } }));
+    }
+}
@@ -3,31 +3,45 @@
 
 use crate::*;
 
-pub struct JwtKeys {
-    encoding: EncodingKey,
-    decoding: DecodingKey,
-}
+use std::net::IpAddr;
 
-impl JwtKeys {
-    pub fn new(secret: &[u8]) -> Self {
-        Self {
-            encoding: EncodingKey::from_secret(secret),
-            decoding: DecodingKey::from_secret(secret),
-        }
-    }
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+
+const REFRESH_TOKEN_LIFETIME: TimeDelta = TimeDelta::days(30);
+/// Start of every API key, which is how they are told apart from JWTs.
+const API_KEY_PREFIX: &str = "rsk_";
+
+/// What our access tokens say and how they are checked: `iss` and `aud`,
+/// how long a token lasts, and how many seconds of clock skew to allow on
+/// `exp`.
+pub struct TokenConfig {
+    pub issuer: String,
+    pub audience: String,
+    pub lifetime: TimeDelta,
+    pub leeway: u64,
 }
 
-pub async fn read_secret(env_var: &str, default: &str) ->
-    Result<String, Box<dyn std::error::Error>>
-{
-    let secretf = std::env::var(env_var).unwrap_or_else(|_| default.to_owned());
-    let secret = tokio::fs::read_to_string(secretf).await?;
-    Ok(secret.trim().to_string())
+fn env_seconds(name: &str, default: u64) -> Result<u64, String> {
+    match std::env::var(name) {
+        Err(_) => Ok(default),
+        Ok(value) => value.parse().map_err(|_| format!("{}: not a number of seconds: {}", name, value)),
+    }
 }
 
-pub async fn make_jwt_keys() -> Result<JwtKeys, Box<dyn std::error::Error>> {
-    let secret = read_secret("JWT_SECRETFILE", "secrets/jwt_secret.txt").await?;
-    Ok(JwtKeys::new(secret.as_bytes()))
+/// Token settings from `JWT_ISSUER`, `JWT_AUDIENCE`, `JWT_LIFETIME` and
+/// `JWT_LEEWAY`, the last two in seconds. Each deployment should set its own
+/// issuer or audience so that tokens from another are refused.
+pub fn make_token_config() -> Result<TokenConfig, Box<dyn std::error::Error>> {
+    let issuer = std::env::var("JWT_ISSUER").unwrap_or_else(|_| "knock-knock.po8.org".to_owned());
+    let audience = std::env::var("JWT_AUDIENCE").unwrap_or_else(|_| "recipe-server".to_owned());
+    let lifetime = env_seconds("JWT_LIFETIME", 15 * 60)?;
+    let leeway = env_seconds("JWT_LEEWAY", 60)?;
+    let lifetime = i64::try_from(lifetime)
+        .ok()
+        .filter(|&seconds| seconds > 0)
+        .and_then(TimeDelta::try_seconds)
+        .ok_or("JWT_LIFETIME: must be a positive number of seconds")?;
+    Ok(TokenConfig { issuer, audience, lifetime, leeway })
 }
 
 #[derive(Debug, thiserror::Error, Serialize)]
@@ -38,29 +52,65 @@ pub enum AuthError {
     TokenCreation,
     #[error("registration error")]
     Registration,
+    #[error("account already exists")]
+    AccountExists,
+    #[error("wrong credentials")]
+    WrongCredentials,
+    #[error("internal error: account storage")]
+    Storage,
+    #[error("role not allowed")]
+    Forbidden,
+    #[error("invalid refresh token")]
+    InvalidRefreshToken,
+    #[error("login session required")]
+    SessionRequired,
+    #[error("api key name missing")]
+    ApiKeyName,
+    #[error("not the author")]
+    NotAuthor,
+    #[error("identity provider login not configured")]
+    OidcDisabled,
+    #[error("identity provider login failed")]
+    OidcLogin,
+    #[error("identity provider error")]
+    IdentityProvider,
+    #[error("identity linked to another account")]
+    IdentityLinked,
+    #[error("authenticator code required")]
+    TotpRequired,
+    #[error("identity provider login to an account with an authenticator")]
+    OidcTotp,
+    #[error("authenticator already enabled")]
+    TotpEnabled,
+    #[error("authenticator not enrolled")]
+    TotpNotEnrolled,
+    #[error("authenticator not enabled")]
+    TotpNotEnabled,
+    #[error("invalid authenticator code")]
+    InvalidTotpCode,
+    /// Seconds until another attempt is allowed.
+    #[error("too many failed attempts")]
+    TooManyAttempts(u64),
 }
 
-impl utoipa::PartialSchema for AuthError {
-    fn schema() -> utoipa::openapi::RefOr<utoipa::openapi::Schema> {
-        serde_json::json!({
-            "status":"401","error":"wrong credentials"
-        }).into()
-    }
-}
-
-impl utoipa::ToSchema for AuthError {}
-
 #[derive(Debug, Serialize, ToSchema)]
 pub struct AuthBody {
     access_token: String,
     token_type: String,
+    /// Seconds until `access_token` expires.
+    #[schema(example = 900)]
+    expires_in: i64,
+    /// Single use; trade it at `/token/refresh` for a new pair of tokens.
+    refresh_token: String,
 }
 
 impl AuthBody {
-    fn new(access_token: String) -> Self {
+    fn new(access_token: String, refresh_token: String, lifetime: TimeDelta) -> Self {
         Self {
             access_token,
             token_type: "Bearer".to_string(),
+            expires_in: lifetime.num_seconds(),
+            refresh_token,
         }
     }
 }
@@ -75,39 +125,106 @@ impl axum::extract::FromRequestParts<SharedAppState> for Claims {
     type Rejection = AuthError;
 
     async fn from_request_parts(parts: &mut http::request::Parts, state: &SharedAppState) -> Result<Self, Self::Rejection> {
-        use jsonwebtoken::{Algorithm, Validation, decode};
+        use jsonwebtoken::{Validation, decode, decode_header};
 
         // Extract the token from the authorization header
         let TypedHeader(Authorization(bearer)) = parts
             .extract::<TypedHeader<Authorization<Bearer>>>()
             .await
             .map_err(|_| AuthError::InvalidToken)?;
-        // Decode the user data
         let appstate = state.read().await;
-        let decoding_key = &appstate.jwt_keys.decoding;
-        let validation = Validation::new(Algorithm::HS512);
+        if bearer.token().starts_with(API_KEY_PREFIX) {
+            return api_key_claims(&appstate, bearer.token()).await;
+        }
+
+        // Decode the user data
+        let header = decode_header(bearer.token()).map_err(|_| AuthError::InvalidToken)?;
+        let key = header
+            .kid
+            .and_then(|kid| appstate.jwt_keys.get(&kid))
+            .ok_or(AuthError::InvalidToken)?;
+        let config = &appstate.token_config;
+        let mut validation = Validation::new(key.algorithm);
+        validation.set_issuer(&[&config.issuer]);
+        validation.set_audience(&[&config.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation.leeway = config.leeway;
         let result = decode::<Claims>(
             bearer.token(),
-            decoding_key,
+            &key.decoding,
             &validation,
         );
-        let token_data = result.map_err(|_| AuthError::Registration)?;
+        let token_data = result.map_err(|_| AuthError::InvalidToken)?;
+        let revoked = token::is_revoked(&appstate.db, &token_data.claims.jti)
+            .await
+            .map_err(|e| {
+                log::warn!("token revocation check failed: {}", e);
+                AuthError::Storage
+            })?;
+        if revoked {
+            return Err(AuthError::InvalidToken);
+        }
         Ok(token_data.claims)
     }
 }
 
-impl IntoResponse for AuthError {
-    fn into_response(self) -> axum::response::Response {
-        let (status, error_message) = match self {
-            AuthError::Registration => (StatusCode::UNAUTHORIZED, "Invalid registration"),
+/// Claims standing in for a token when a request carries an API key
+/// instead.
+async fn api_key_claims(appstate: &AppState, key: &str) -> Result<Claims, AuthError> {
+    let now = Utc::now();
+    let owner = apikey::authenticate(&appstate.db, &hash_token(key), now.timestamp())
+        .await
+        .map_err(storage_error)?
+        .ok_or(AuthError::InvalidToken)?;
+    let role = match owner.scope {
+        apikey::Scope::Read => user::Role::Reader,
+        apikey::Scope::Write => owner.role,
+    };
+    let config = &appstate.token_config;
+    Ok(Claims {
+        iss: config.issuer.clone(),
+        aud: config.audience.clone(),
+        sub: owner.user_id.to_string(),
+        exp: u64::try_from((now + config.lifetime).timestamp()).unwrap(),
+        jti: format!("api-key-{}", owner.key_id),
+        role,
+        api_key: Some(owner.key_id),
+    })
+}
+
+impl AuthError {
+    /// Status and message this error is reported with.
+    pub fn status_message(&self) -> (StatusCode, &'static str) {
+        match self {
+            AuthError::Registration => (StatusCode::BAD_REQUEST, "Invalid registration"),
+            AuthError::AccountExists => (StatusCode::CONFLICT, "Email already registered"),
+            AuthError::WrongCredentials => (StatusCode::UNAUTHORIZED, "Wrong credentials"),
             AuthError::TokenCreation => (StatusCode::INTERNAL_SERVER_ERROR, "Token creation error"),
+            AuthError::Storage => (StatusCode::INTERNAL_SERVER_ERROR, "Account storage error"),
             AuthError::InvalidToken => (StatusCode::UNAUTHORIZED, "Invalid token"),
-        };
-        let body = Json(serde_json::json!({
-            "status": status.as_u16(),
-            "error": error_message,
-        }));
-        (status, body).into_response()
+            AuthError::Forbidden => (StatusCode::FORBIDDEN, "Role not allowed"),
+            AuthError::InvalidRefreshToken => (StatusCode::UNAUTHORIZED, "Invalid refresh token"),
+            AuthError::SessionRequired => (StatusCode::FORBIDDEN, "Not allowed with an API key"),
+            AuthError::ApiKeyName => (StatusCode::BAD_REQUEST, "API key needs a name"),
+            AuthError::NotAuthor => (StatusCode::FORBIDDEN, "Only the author or an editor may change this recipe"),
+            AuthError::OidcDisabled => (StatusCode::NOT_FOUND, "Identity provider login is not configured"),
+            AuthError::OidcLogin => (StatusCode::UNAUTHORIZED, "Identity provider login failed"),
+            AuthError::IdentityProvider => (StatusCode::BAD_GATEWAY, "Identity provider error"),
+            AuthError::IdentityLinked => (StatusCode::CONFLICT, "Identity already linked to another account"),
+            AuthError::TotpRequired => (StatusCode::UNAUTHORIZED, "Authenticator code required"),
+            AuthError::OidcTotp => (StatusCode::FORBIDDEN, "Account has an authenticator; sign in with password and code"),
+            AuthError::TotpEnabled => (StatusCode::CONFLICT, "Authenticator already enabled"),
+            AuthError::TotpNotEnrolled => (StatusCode::NOT_FOUND, "Start authenticator enrollment first"),
+            AuthError::TotpNotEnabled => (StatusCode::NOT_FOUND, "Authenticator not enabled"),
+            AuthError::InvalidTotpCode => (StatusCode::BAD_REQUEST, "Invalid authenticator code"),
+            AuthError::TooManyAttempts(_) => (StatusCode::TOO_MANY_REQUESTS, "Too many failed attempts, try again later"),
+        }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> axum::response::Response {
+        ApiError::Auth(self).into_response()
     }
 }
 
@@ -117,34 +234,631 @@ pub struct Registration {
     full_name: String,
     #[schema(example = "johnsmith@example.org")]
     email: String,
+    /// At least 8 characters.
     #[schema(example = "password123")]
     password: String,
 }
 
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct Login {
+    #[schema(example = "johnsmith@example.org")]
+    email: String,
+    #[schema(example = "password123")]
+    password: String,
+    /// Code from the authenticator app, or an unused recovery code, for
+    /// accounts with two-factor login; without it those get 401
+    /// "Authenticator code required".
+    #[serde(default)]
+    #[schema(example = "123456")]
+    totp_code: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Claims {
     #[schema(example = "knock-knock.po8.org")]
     iss: String,
-    #[schema(example = "John Smith <johnsmith@example.org>")]
+    #[schema(example = "recipe-server")]
+    aud: String,
+    /// Id of the user the token was issued to.
+    #[schema(example = "1")]
     sub: String,
     #[schema(example = "1717630066")]
     exp: u64,
+    /// Unique token id, used to revoke it.
+    #[schema(example = "q3Wc5y0oVZ8CqS7MYYlAfQ")]
+    jti: String,
+    /// Role of the user when the token was issued.
+    role: user::Role,
+    /// Set when the request was made with this API key rather than a token.
+    #[serde(skip)]
+    #[schema(ignore)]
+    api_key: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct NewApiKey {
+    #[schema(example = "nightly import")]
+    name: String,
+    scope: apikey::Scope,
+}
+
+/// A newly created API key. `key` is not stored and cannot be shown again.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedApiKey {
+    #[schema(example = "rsk_Jx3qV0c1mN8yT2aLp9WfZ4kH7sD6eR5uB1oQ3iX0gYc")]
+    key: String,
+    #[serde(flatten)]
+    info: apikey::ApiKeyInfo,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct RefreshRequest {
+    #[schema(example = "zlX4PuJ1Ym3t2W0xQ5lS9bYzV7m2fJb8o3hV4kq0nCk")]
+    refresh_token: String,
+}
+
+impl Claims {
+    /// Id of the user the token was issued to.
+    pub fn sub(&self) -> &str {
+        &self.sub
+    }
+
+    pub fn user_id(&self) -> Result<i64, AuthError> {
+        self.sub.parse().map_err(|_| AuthError::InvalidToken)
+    }
+
+    /// Whether the caller may change or delete a recipe added by
+    /// `author_id`: its author can, and so can editors.
+    pub fn can_modify(&self, author_id: Option<i64>) -> bool {
+        self.role >= user::Role::Editor || author_id.is_some_and(|id| self.user_id().ok() == Some(id))
+    }
+
+    /// Refuse requests made with an API key, for actions that must not be
+    /// reachable from one, like minting more keys.
+    pub fn require_session(&self) -> Result<(), AuthError> {
+        match self.api_key {
+            Some(_) => Err(AuthError::SessionRequired),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Markers naming the least role a route accepts, for use with
+/// [`Authorized`].
+pub mod role {
+    use crate::user::Role;
+
+    pub trait MinRole {
+        const ROLE: Role;
+    }
+
+    pub struct Contributor;
+    pub struct Editor;
+    pub struct Admin;
+
+    impl MinRole for Contributor {
+        const ROLE: Role = Role::Contributor;
+    }
+
+    impl MinRole for Editor {
+        const ROLE: Role = Role::Editor;
+    }
+
+    impl MinRole for Admin {
+        const ROLE: Role = Role::Admin;
+    }
+}
+
+/// Claims of a caller holding at least role `R`; the request is rejected
+/// with 403 otherwise. A handler declares what it needs by taking e.g.
+/// `Authorized<role::Contributor>`.
+pub struct Authorized<R> {
+    pub claims: Claims,
+    role: std::marker::PhantomData<R>,
+}
+
+impl<R: role::MinRole> axum::extract::FromRequestParts<SharedAppState> for Authorized<R> {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut http::request::Parts, state: &SharedAppState) -> Result<Self, Self::Rejection> {
+        let claims = Claims::from_request_parts(parts, state).await?;
+        if claims.role < R::ROLE {
+            return Err(AuthError::Forbidden);
+        }
+        Ok(Self { claims, role: std::marker::PhantomData })
+    }
+}
+
+// Argon2 is deliberately slow, so hashing runs off the async workers.
+async fn hash_password(password: String) -> Result<String, AuthError> {
+    use argon2::password_hash::{PasswordHasher, SaltString, rand_core::OsRng};
+
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        argon2::Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+    .await
+    .map_err(|_| AuthError::Storage)?
+    .map_err(|_| AuthError::Storage)
+}
+
+// Checked in place of a missing password hash, so a login for an unknown
+// email or a passwordless account takes as long as a wrong password.
+static DUMMY_HASH: std::sync::LazyLock<String> = std::sync::LazyLock::new(|| {
+    use argon2::password_hash::{PasswordHasher, SaltString, rand_core::{OsRng, RngCore}};
+
+    let mut password = [0u8; 32];
+    OsRng.fill_bytes(&mut password);
+    let salt = SaltString::generate(&mut OsRng);
+    argon2::Argon2::default()
+        .hash_password(&password, &salt)
+        .expect("dummy password hashes")
+        .to_string()
+});
+
+/// Whether `password` matches `password_hash`. An empty hash never matches,
+/// but costs the same to check.
+async fn verify_password(password: String, password_hash: String) -> bool {
+    use argon2::password_hash::{PasswordHash, PasswordVerifier};
+
+    tokio::task::spawn_blocking(move || {
+        let (hash, real) = match password_hash.as_str() {
+            "" => (DUMMY_HASH.as_str(), false),
+            hash => (hash, true),
+        };
+        let Ok(hash) = PasswordHash::new(hash) else {
+            return false;
+        };
+        argon2::Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+            && real
+    })
+    .await
+    .unwrap_or(false)
 }
 
-pub fn make_jwt_token(appstate: &AppState, registration: &Registration) -> Result<AuthBody, AuthError> {
-    use jsonwebtoken::{Algorithm, Header, encode};
-    
-    if registration.password != appstate.reg_key {
+/// Create an account and return a token for it. Failed attempts count
+/// against the address they come from.
+pub async fn register(
+    appstate: &AppState,
+    registration: &Registration,
+    ip: IpAddr,
+    request_id: Option<&str>,
+) -> Result<AuthBody, AuthError> {
+    let keys = [throttle::Key::Ip(ip)];
+    throttle::reserve(&appstate.db, &keys).await?;
+    let registered = create_account(appstate, registration, request_id).await;
+    if !matches!(registered, Err(AuthError::Registration | AuthError::AccountExists)) {
+        throttle::release(&appstate.db, &keys).await;
+    }
+    registered
+}
+
+async fn create_account(
+    appstate: &AppState,
+    registration: &Registration,
+    request_id: Option<&str>,
+) -> Result<AuthBody, AuthError> {
+    let full_name = registration.full_name.trim();
+    let email = registration.email.trim();
+    if full_name.is_empty() || !email.contains('@') || registration.password.chars().count() < 8 {
         return Err(AuthError::Registration);
     }
 
-    let iss = "knock-knock.po8.org".to_string();
-    let sub = format!("{} <{}>", registration.full_name, registration.email);
-    let exp = (Utc::now() + TimeDelta::days(1)).timestamp();
+    let password_hash = hash_password(registration.password.clone()).await?;
+    let mut tx = appstate.db.begin().await.map_err(storage_error)?;
+    let (user_id, role) = match user::add(&mut *tx, full_name, email, &password_hash).await {
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Err(AuthError::AccountExists);
+        }
+        Err(e) => {
+            log::warn!("user insert failed: {}", e);
+            return Err(AuthError::Storage);
+        }
+        Ok(added) => added,
+    };
+    let event = audit::Event::new(audit::Action::Register, request_id)
+        .actor(Some(user_id))
+        .target(user_id)
+        .diff(serde_json::json!({ "role": { "before": null, "after": role } }));
+    audit::write(&mut *tx, event).await.map_err(storage_error)?;
+    tx.commit().await.map_err(storage_error)?;
+    issue_tokens(appstate, user_id, role, None).await
+}
+
+/// Check an email and password and return a token for that account.
+pub async fn login(appstate: &AppState, login: &Login, ip: IpAddr, request_id: Option<&str>) -> Result<AuthBody, AuthError> {
+    let user = check_login(appstate, login, ip, request_id).await?;
+    issue_tokens(appstate, user.id, user.role, None).await
+}
+
+/// Check an email and password and return the account they belong to,
+/// recording the attempt either way. Attempts count against both the
+/// address and the email before the password is looked at, and are given
+/// back unless the credentials were wrong; either being locked out refuses
+/// the attempt.
+pub async fn check_login(
+    appstate: &AppState,
+    login: &Login,
+    ip: IpAddr,
+    request_id: Option<&str>,
+) -> Result<user::User, AuthError> {
+    let keys = [throttle::Key::Ip(ip), throttle::Key::Account(&login.email)];
+    throttle::reserve(&appstate.db, &keys).await?;
+    let checked = verify_login(appstate, login, request_id).await;
+    match checked {
+        Err(AuthError::WrongCredentials) => (),
+        Ok(_) => throttle::record_success(&appstate.db, &keys).await,
+        Err(_) => throttle::release(&appstate.db, &keys).await,
+    }
+    checked
+}
+
+async fn verify_login(appstate: &AppState, login: &Login, request_id: Option<&str>) -> Result<user::User, AuthError> {
+    let user = user::get_by_email(&appstate.db, login.email.trim())
+        .await
+        .map_err(|e| {
+            log::warn!("user fetch failed: {}", e);
+            AuthError::Storage
+        })?;
+    let Some(user) = user else {
+        verify_password(login.password.clone(), String::new()).await;
+        let event = audit::Event::new(audit::Action::LoginFailed, request_id);
+        audit::record(&appstate.db, event).await;
+        return Err(AuthError::WrongCredentials);
+    };
+    if !verify_password(login.password.clone(), user.password_hash.clone()).await {
+        let event = audit::Event::new(audit::Action::LoginFailed, request_id).target(user.id);
+        audit::record(&appstate.db, event).await;
+        return Err(AuthError::WrongCredentials);
+    }
+    match totp::check(&appstate.db, user.id, login.totp_code.as_deref()).await {
+        Err(AuthError::WrongCredentials) => {
+            let event = audit::Event::new(audit::Action::LoginFailed, request_id).target(user.id);
+            audit::record(&appstate.db, event).await;
+            return Err(AuthError::WrongCredentials);
+        }
+        Err(e) => return Err(e),
+        Ok(()) => (),
+    }
+    let event = audit::Event::new(audit::Action::Login, request_id)
+        .actor(Some(user.id))
+        .target(user.id);
+    audit::record(&appstate.db, event).await;
+    Ok(user)
+}
+
+pub fn random_token(len: usize) -> String {
+    use rand::RngCore;
+
+    let mut bytes = vec![0u8; len];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// Only hashes of refresh tokens are stored, so a leaked database cannot be
+// used to sign in.
+pub fn hash_token(token: &str) -> String {
+    use sha2::{Digest, Sha256};
+
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Make an API key for the caller. Only a login session can do this, so a
+/// leaked read key cannot be used to mint a write key.
+pub async fn create_api_key(
+    appstate: &AppState,
+    claims: &Claims,
+    new_key: &NewApiKey,
+    request_id: Option<&str>,
+) -> Result<CreatedApiKey, AuthError> {
+    claims.require_session()?;
+    let user_id = claims.user_id()?;
+    let name = new_key.name.trim();
+    if name.is_empty() {
+        return Err(AuthError::ApiKeyName);
+    }
+
+    let key = format!("{}{}", API_KEY_PREFIX, random_token(32));
+    let prefix = &key[..API_KEY_PREFIX.len() + 4];
+    let now = Utc::now().timestamp();
+    let mut tx = appstate.db.begin().await.map_err(storage_error)?;
+    let key_id = apikey::add(&mut *tx, user_id, name, prefix, &hash_token(&key), new_key.scope, now)
+        .await
+        .map_err(storage_error)?;
+    let info = apikey::get(&mut *tx, user_id, key_id)
+        .await
+        .map_err(storage_error)?
+        .ok_or(AuthError::Storage)?;
+    let event = audit::Event::new(audit::Action::ApiKeyCreate, request_id)
+        .actor(Some(user_id))
+        .target(key_id)
+        .diff(audit::diff(None, Some(&serde_json::json!(info))));
+    audit::write(&mut *tx, event).await.map_err(storage_error)?;
+    tx.commit().await.map_err(storage_error)?;
+    Ok(CreatedApiKey { key, info })
+}
+
+pub fn storage_error(e: sqlx::Error) -> AuthError {
+    log::warn!("token storage failed: {}", e);
+    AuthError::Storage
+}
+
+/// A new access token plus a refresh token in `family`, or in a new family
+/// for a fresh login.
+pub async fn issue_tokens(
+    appstate: &AppState,
+    user_id: i64,
+    role: user::Role,
+    family: Option<String>,
+) -> Result<AuthBody, AuthError> {
+    let access_token = make_jwt_token(appstate, user_id, role)?;
+    let refresh_token = random_token(32);
+    let family = family.unwrap_or_else(|| random_token(16));
+    let expires_at = (Utc::now() + REFRESH_TOKEN_LIFETIME).timestamp();
+    token::add_refresh(&appstate.db, user_id, &hash_token(&refresh_token), &family, expires_at)
+        .await
+        .map_err(storage_error)?;
+    Ok(AuthBody::new(access_token, refresh_token, appstate.token_config.lifetime))
+}
+
+/// Trade a refresh token for a new access token and refresh token. A refresh
+/// token that was already used means it leaked, so the whole family is
+/// revoked and its holder has to log in again. Invalid tokens count as
+/// failures against the address they come from.
+pub async fn refresh(
+    appstate: &AppState,
+    request: &RefreshRequest,
+    ip: IpAddr,
+    request_id: Option<&str>,
+) -> Result<AuthBody, AuthError> {
+    let keys = [throttle::Key::Ip(ip)];
+    throttle::reserve(&appstate.db, &keys).await?;
+    let refreshed = rotate_refresh_token(appstate, request, request_id).await;
+    if !matches!(refreshed, Err(AuthError::InvalidRefreshToken)) {
+        throttle::release(&appstate.db, &keys).await;
+    }
+    refreshed
+}
+
+async fn rotate_refresh_token(
+    appstate: &AppState,
+    request: &RefreshRequest,
+    request_id: Option<&str>,
+) -> Result<AuthBody, AuthError> {
+    let now = Utc::now().timestamp();
+    let found = token::get_refresh(&appstate.db, &hash_token(&request.refresh_token))
+        .await
+        .map_err(storage_error)?;
+    let Some((refresh, live)) = found else {
+        return Err(AuthError::InvalidRefreshToken);
+    };
+    let live = live && token::revoke_refresh(&appstate.db, refresh.id, now)
+        .await
+        .map_err(storage_error)?;
+    if !live {
+        log::warn!("refresh token reused for user {}", refresh.user_id);
+        token::revoke_family(&appstate.db, &refresh.family, now)
+            .await
+            .map_err(storage_error)?;
+        return Err(AuthError::InvalidRefreshToken);
+    }
+    if refresh.expires_at < now {
+        return Err(AuthError::InvalidRefreshToken);
+    }
+
+    // Pick up role changes made since the last token was issued.
+    let role = user::get_role(&appstate.db, refresh.user_id)
+        .await
+        .map_err(storage_error)?
+        .ok_or(AuthError::InvalidRefreshToken)?;
+    let event = audit::Event::new(audit::Action::TokenRefresh, request_id)
+        .actor(Some(refresh.user_id))
+        .target(refresh.user_id);
+    audit::record(&appstate.db, event).await;
+    issue_tokens(appstate, refresh.user_id, role, Some(refresh.family)).await
+}
+
+/// Revoke the access token in `claims` and the refresh token family of
+/// `request`, if it belongs to the same user.
+pub async fn logout(
+    appstate: &AppState,
+    claims: &Claims,
+    request: &RefreshRequest,
+    request_id: Option<&str>,
+) -> Result<(), AuthError> {
+    claims.require_session()?;
+    let now = Utc::now().timestamp();
+    let found = token::get_refresh(&appstate.db, &hash_token(&request.refresh_token))
+        .await
+        .map_err(storage_error)?;
+    if let Some((refresh, _)) = found
+        && refresh.user_id.to_string() == claims.sub
+    {
+        token::revoke_family(&appstate.db, &refresh.family, now)
+            .await
+            .map_err(storage_error)?;
+    }
+    let exp = i64::try_from(claims.exp).unwrap_or(i64::MAX);
+    token::revoke_access(&appstate.db, &claims.jti, exp, now)
+        .await
+        .map_err(storage_error)?;
+    let event = audit::Event::new(audit::Action::Logout, request_id).actor(claims.user_id().ok());
+    audit::record(&appstate.db, event).await;
+    Ok(())
+}
+
+fn make_jwt_token(appstate: &AppState, user_id: i64, role: user::Role) -> Result<String, AuthError> {
+    use jsonwebtoken::{Header, encode};
+
+    let config = &appstate.token_config;
+    let iss = config.issuer.clone();
+    let aud = config.audience.clone();
+    let sub = user_id.to_string();
+    let exp = (Utc::now() + config.lifetime).timestamp();
     let exp = u64::try_from(exp).unwrap();
-    let claims = Claims { iss, sub, exp };
-    let header = Header::new(Algorithm::HS512);
-    let token = encode(&header, &claims, &appstate.jwt_keys.encoding)
-        .map_err(|_| AuthError::TokenCreation)?;
-    Ok(AuthBody::new(token))
+    let jti = random_token(16);
+    let claims = Claims { iss, aud, sub, exp, jti, role, api_key: None };
+    let key = appstate.jwt_keys.current();
+    let mut header = Header::new(key.algorithm);
+    header.kid = Some(key.kid.clone());
+    encode(&header, &claims, &key.encoding)
+        .map_err(|_| AuthError::TokenCreation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{add_key, key_dir, load_keys, test_appstate};
+
+    use axum::extract::FromRequestParts;
+    use jsonwebtoken::{Header, encode};
+
+    /// Claims a token from `appstate` would have, expiring `exp_in` from now.
+    fn claims(appstate: &AppState, exp_in: TimeDelta) -> Claims {
+        let config = &appstate.token_config;
+        Claims {
+            iss: config.issuer.clone(),
+            aud: config.audience.clone(),
+            sub: "1".to_string(),
+            exp: u64::try_from((Utc::now() + exp_in).timestamp()).unwrap(),
+            jti: random_token(16),
+            role: user::Role::Reader,
+            api_key: None,
+        }
+    }
+
+    /// `claims` signed by the key `signer`, naming key `kid`.
+    fn sign(appstate: &AppState, claims: &Claims, signer: &str, kid: Option<&str>) -> String {
+        let key = appstate.jwt_keys.get(signer).unwrap();
+        let header = Header { kid: kid.map(str::to_string), ..Header::new(key.algorithm) };
+        encode(&header, claims, &key.encoding).unwrap()
+    }
+
+    /// Run a request bearing `token` through the [`Claims`] extractor.
+    async fn extract(state: &SharedAppState, token: &str) -> Result<Claims, AuthError> {
+        let request = http::Request::builder()
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(())
+            .unwrap();
+        let (mut parts, ()) = request.into_parts();
+        Claims::from_request_parts(&mut parts, state).await
+    }
+
+    #[tokio::test]
+    async fn claims_refuse_other_issuer_audience_and_expired_tokens() {
+        let appstate = test_appstate().await;
+        let kid = appstate.jwt_keys.current().kid.clone();
+        let leeway = TimeDelta::seconds(appstate.token_config.leeway as i64);
+        let lifetime = appstate.token_config.lifetime;
+        let cases = [
+            ("issued", claims(&appstate, lifetime), true),
+            ("other issuer", Claims { iss: "another-issuer".to_string(), ..claims(&appstate, lifetime) }, false),
+            ("other audience", Claims { aud: "another-audience".to_string(), ..claims(&appstate, lifetime) }, false),
+            ("expired within leeway", claims(&appstate, -leeway / 2), true),
+            ("expired beyond leeway", claims(&appstate, -leeway - TimeDelta::seconds(5)), false),
+        ];
+        let tokens: Vec<_> = cases
+            .into_iter()
+            .map(|(case, claims, accepted)| (case, sign(&appstate, &claims, &kid, Some(&kid)), accepted))
+            .collect();
+        let state = Arc::new(RwLock::new(appstate));
+        for (case, token, accepted) in tokens {
+            let extracted = extract(&state, &token).await;
+            assert_eq!(extracted.is_ok(), accepted, "{}", case);
+            if !accepted {
+                assert!(matches!(extracted, Err(AuthError::InvalidToken)), "{}", case);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn claims_check_token_with_key_named_by_kid() {
+        let mut appstate = test_appstate().await;
+        let dir = key_dir();
+        add_key(&dir, "current", TimeDelta::days(10));
+        add_key(&dir, "next", TimeDelta::zero());
+        appstate.jwt_keys = load_keys(&dir).await;
+        assert_eq!(appstate.jwt_keys.current().kid, "current");
+        let claims = claims(&appstate, TimeDelta::minutes(15));
+        let cases = [
+            ("current key", sign(&appstate, &claims, "current", Some("current")), true),
+            ("published next key", sign(&appstate, &claims, "next", Some("next")), true),
+            ("other key's kid", sign(&appstate, &claims, "next", Some("current")), false),
+            ("unknown kid", sign(&appstate, &claims, "current", Some("retired")), false),
+            ("no kid", sign(&appstate, &claims, "current", None), false),
+        ];
+        let state = Arc::new(RwLock::new(appstate));
+        for (case, token, accepted) in cases {
+            assert_eq!(extract(&state, &token).await.is_ok(), accepted, "{}", case);
+        }
+    }
+
+    #[tokio::test]
+    async fn reused_refresh_token_revokes_its_family() {
+        let appstate = test_appstate().await;
+        let ip = "203.0.113.7".parse().unwrap();
+        let (user_id, role) = user::add(&appstate.db, "Jo Cook", "jo@example.org", "$argon2id$hash").await.unwrap();
+        let refresh_with = async |body: &AuthBody| {
+            let request = RefreshRequest { refresh_token: body.refresh_token.clone() };
+            refresh(&appstate, &request, ip, None).await
+        };
+        let first = issue_tokens(&appstate, user_id, role, None).await.unwrap();
+        let other_login = issue_tokens(&appstate, user_id, role, None).await.unwrap();
+        let second = refresh_with(&first).await.unwrap();
+
+        assert!(matches!(refresh_with(&first).await, Err(AuthError::InvalidRefreshToken)));
+        assert!(matches!(refresh_with(&second).await, Err(AuthError::InvalidRefreshToken)));
+        assert!(refresh_with(&other_login).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn api_key_scope_limits_role() {
+        let appstate = test_appstate().await;
+        let (user_id, _) = user::add(&appstate.db, "Jo Cook", "jo@example.org", "$argon2id$hash").await.unwrap();
+        user::set_role(&appstate.db, user_id, user::Role::Editor).await.unwrap();
+        let now = Utc::now().timestamp();
+        for (scope, role) in [(apikey::Scope::Read, user::Role::Reader), (apikey::Scope::Write, user::Role::Editor)] {
+            let key = format!("{}{}", API_KEY_PREFIX, random_token(32));
+            let prefix = &key[..API_KEY_PREFIX.len() + 4];
+            apikey::add(&appstate.db, user_id, "test", prefix, &hash_token(&key), scope, now)
+                .await
+                .unwrap();
+            let claims = api_key_claims(&appstate, &key).await.unwrap();
+            assert_eq!(claims.role, role);
+            assert_eq!(claims.user_id().unwrap(), user_id);
+            assert!(matches!(claims.require_session(), Err(AuthError::SessionRequired)));
+        }
+        let unknown = format!("{}{}", API_KEY_PREFIX, random_token(32));
+        assert!(matches!(api_key_claims(&appstate, &unknown).await, Err(AuthError::InvalidToken)));
+    }
+
+    #[tokio::test]
+    async fn concurrent_bad_logins_stop_at_free_failures() {
+        let appstate = Arc::new(test_appstate().await);
+        let mut attempts = tokio::task::JoinSet::new();
+        for _ in 0..20 {
+            let appstate = appstate.clone();
+            attempts.spawn(async move {
+                let login = Login {
+                    email: "jo@example.org".to_string(),
+                    password: "not the password".to_string(),
+                    totp_code: None,
+                };
+                check_login(&appstate, &login, "203.0.113.7".parse().unwrap(), None).await
+            });
+        }
+        let mut wrong = 0;
+        while let Some(attempt) = attempts.join_next().await {
+            match attempt.unwrap() {
+                Err(AuthError::WrongCredentials) => wrong += 1,
+                Err(AuthError::TooManyAttempts(_)) => (),
+                other => panic!("unexpected login result: {:?}", other.map(|user| user.id)),
+            }
+        }
+        assert_eq!(wrong, 5);
+    }
 }
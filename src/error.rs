@@ -1,4 +1,7 @@
 extern crate serde_json;
+use crate::*;
+
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -10,3 +13,136 @@ pub enum RecipeError {
     #[error("invalid database uri: {0}")]
     InvalidDbUri(String),
 }
+
+/// Failure of a JSON API request, sent as an RFC 7807 problem document
+/// ([`Problem`]).
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    NotFound(String),
+    /// The request was understood but what it asks for is not acceptable.
+    #[error("{0}")]
+    Validation(String),
+    #[error("{0}")]
+    Conflict(String),
+    /// An `If-Match` precondition did not hold.
+    #[error("{0}")]
+    PreconditionFailed(String),
+    /// A change was sent without the `If-Match` header it needs.
+    #[error("{0}")]
+    PreconditionRequired(String),
+    /// The body, path or query string could not be read; keeps the status
+    /// axum chose for it, such as 415 or 422.
+    #[error("{1}")]
+    Rejected(StatusCode, String),
+    #[error(transparent)]
+    Auth(#[from] authjwt::AuthError),
+    /// Details are logged where the error happens rather than sent.
+    #[error("internal error")]
+    Internal,
+}
+
+/// RFC 7807 problem details, with content type `application/problem+json`.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Problem {
+    /// Always `about:blank`: `status` and `title` say what went wrong.
+    #[serde(rename = "type")]
+    #[schema(example = "about:blank")]
+    problem_type: &'static str,
+    #[schema(example = "Not Found")]
+    title: String,
+    #[schema(example = 404)]
+    status: u16,
+    #[schema(example = "no recipe with id 7")]
+    detail: String,
+}
+
+impl utoipa::PartialSchema for ApiError {
+    fn schema() -> utoipa::openapi::RefOr<utoipa::openapi::Schema> {
+        Problem::schema()
+    }
+}
+
+impl utoipa::ToSchema for ApiError {}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        use sqlx::error::ErrorKind;
+
+        match &e {
+            sqlx::Error::RowNotFound => return ApiError::NotFound("no matching record".to_string()),
+            sqlx::Error::Database(db) => match db.kind() {
+                ErrorKind::UniqueViolation => {
+                    return ApiError::Conflict("a record with this id or name already exists".to_string());
+                }
+                ErrorKind::ForeignKeyViolation | ErrorKind::NotNullViolation | ErrorKind::CheckViolation => {
+                    return ApiError::Validation(db.message().to_string());
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+        log::warn!("database error: {}", e);
+        ApiError::Internal
+    }
+}
+
+impl From<RecipeError> for ApiError {
+    fn from(e: RecipeError) -> Self {
+        match e {
+            RecipeError::RecipeMisformat(e) => ApiError::Validation(e.to_string()),
+            e => {
+                log::warn!("recipe error: {}", e);
+                ApiError::Internal
+            }
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::Rejected(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        ApiError::Rejected(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::Rejected(rejection.status(), rejection.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        let (status, detail) = match &self {
+            ApiError::NotFound(detail) => (StatusCode::NOT_FOUND, detail.clone()),
+            ApiError::Validation(detail) => (StatusCode::BAD_REQUEST, detail.clone()),
+            ApiError::Conflict(detail) => (StatusCode::CONFLICT, detail.clone()),
+            ApiError::PreconditionFailed(detail) => (StatusCode::PRECONDITION_FAILED, detail.clone()),
+            ApiError::PreconditionRequired(detail) => (StatusCode::PRECONDITION_REQUIRED, detail.clone()),
+            ApiError::Rejected(status, detail) => (*status, detail.clone()),
+            ApiError::Auth(e) => {
+                let (status, message) = e.status_message();
+                (status, message.to_string())
+            }
+            ApiError::Internal => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
+        };
+        let problem = Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or_default().to_string(),
+            status: status.as_u16(),
+            detail,
+        };
+        let content_type = [(http::header::CONTENT_TYPE, "application/problem+json")];
+        let mut response = (status, content_type, Json(problem)).into_response();
+        if let ApiError::Auth(authjwt::AuthError::TooManyAttempts(seconds)) = self {
+            response.headers_mut().insert(http::header::RETRY_AFTER, seconds.into());
+        }
+        response
+    }
+}
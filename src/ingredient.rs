@@ -0,0 +1,542 @@
+use crate::*;
+
+use std::fmt;
+
+// Canonical unit name, its plural when written out in full, and the
+// spellings that map onto it.
+const UNITS: &[(&str, Option<&str>, &[&str])] = &[
+    ("g", None, &["g", "gr", "gram", "grams", "gramme", "grammes"]),
+    ("kg", None, &["kg", "kgs", "kilo", "kilos", "kilogram", "kilograms"]),
+    ("mg", None, &["mg", "milligram", "milligrams"]),
+    ("ml", None, &["ml", "milliliter", "milliliters", "millilitre", "millilitres"]),
+    ("cl", None, &["cl", "centiliter", "centiliters", "centilitre", "centilitres"]),
+    ("dl", None, &["dl", "deciliter", "deciliters", "decilitre", "decilitres"]),
+    ("l", None, &["l", "liter", "liters", "litre", "litres"]),
+    ("tsp", None, &["tsp", "tsps", "teaspoon", "teaspoons"]),
+    ("tbsp", None, &["tbsp", "tbsps", "tbs", "tablespoon", "tablespoons"]),
+    ("cup", Some("cups"), &["cup", "cups"]),
+    ("fl oz", None, &["fl oz", "fl. oz", "fluid ounce", "fluid ounces"]),
+    ("oz", None, &["oz", "ounce", "ounces"]),
+    ("lb", None, &["lb", "lbs", "pound", "pounds"]),
+    ("pt", None, &["pt", "pint", "pints"]),
+    ("qt", None, &["qt", "quart", "quarts"]),
+    ("gal", None, &["gal", "gallon", "gallons"]),
+    ("pinch", Some("pinches"), &["pinch", "pinches"]),
+    ("dash", Some("dashes"), &["dash", "dashes"]),
+    ("knob", Some("knobs"), &["knob", "knobs"]),
+    ("clove", Some("cloves"), &["clove", "cloves"]),
+    ("sprig", Some("sprigs"), &["sprig", "sprigs"]),
+    ("glass", Some("glasses"), &["glass", "glasses"]),
+    ("packet", Some("packets"), &["packet", "packets"]),
+    ("sachet", Some("sachets"), &["sachet", "sachets"]),
+    ("handful", Some("handfuls"), &["handful", "handfuls"]),
+    ("slice", Some("slices"), &["slice", "slices"]),
+    ("can", Some("cans"), &["can", "cans"]),
+    ("bunch", Some("bunches"), &["bunch", "bunches"]),
+    ("stick", Some("sticks"), &["stick", "sticks"]),
+];
+
+// Written straight after the number, as in "100ml".
+const GLUED_UNITS: &[&str] = &["g", "kg", "mg", "ml", "cl", "dl", "l"];
+
+const NAME_SUFFIXES: &[&str] = &["as needed", "to taste", "as required"];
+
+// Plural and singular of words the suffix rules in `singular` and `plural`
+// get wrong.
+const IRREGULAR_PLURALS: &[(&str, &str)] = &[
+    ("cookies", "cookie"),
+    ("brownies", "brownie"),
+    ("smoothies", "smoothie"),
+    ("pies", "pie"),
+    ("sloes", "sloe"),
+    ("avocados", "avocado"),
+    ("pistachios", "pistachio"),
+    ("jalapenos", "jalapeno"),
+    ("quiches", "quiche"),
+    ("leaves", "leaf"),
+    ("loaves", "loaf"),
+    ("halves", "half"),
+    ("molasses", "molasses"),
+    ("hummus", "hummus"),
+    ("couscous", "couscous"),
+    ("asparagus", "asparagus"),
+    ("grits", "grits"),
+    ("oats", "oats"),
+];
+
+/// One entry of a recipe's ingredient list, split into its parts.
+///
+/// `text` keeps the entry as it was written. The API also accepts a bare
+/// string such as `"1/2 tsp baking powder"` in place of the object, which is
+/// parsed into the other fields.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(try_from = "IngredientRepr")]
+pub struct Ingredient {
+    #[schema(example = "1-2 tbsp sugar")]
+    pub text: String,
+    #[schema(example = 1.0)]
+    pub quantity: Option<f64>,
+    /// Upper bound when the quantity is a range.
+    #[schema(example = 2.0)]
+    pub quantity_max: Option<f64>,
+    #[schema(example = "tbsp")]
+    pub unit: Option<String>,
+    #[schema(example = "sugar")]
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IngredientRepr {
+    Text(String),
+    Fields {
+        text: Option<String>,
+        quantity: Option<f64>,
+        quantity_max: Option<f64>,
+        unit: Option<String>,
+        name: Option<String>,
+    },
+}
+
+impl TryFrom<IngredientRepr> for Ingredient {
+    type Error = String;
+
+    fn try_from(repr: IngredientRepr) -> Result<Self, Self::Error> {
+        match repr {
+            IngredientRepr::Text(text) => Ok(Ingredient::parse(&text)),
+            IngredientRepr::Fields { text, name: None, .. } => match text {
+                Some(text) => Ok(Ingredient::parse(&text)),
+                None => Err("ingredient needs a name or text".to_string()),
+            },
+            IngredientRepr::Fields { text, quantity, quantity_max, unit, name: Some(name) } => {
+                let unit = unit.map(|u| canonical_unit(&u).unwrap_or(&u).to_string());
+                let mut ingredient = Ingredient {
+                    text: String::new(),
+                    quantity,
+                    quantity_max: quantity.and(quantity_max),
+                    unit,
+                    name: canonical_name(&name),
+                };
+                ingredient.text = text.unwrap_or_else(|| ingredient.to_string());
+                Ok(ingredient)
+            }
+        }
+    }
+}
+
+impl Ingredient {
+    /// Split free text like `"100ml milk"` or `"2-3 cloves of garlic"` into
+    /// quantity, unit and name. Anything that does not start with a quantity
+    /// is kept whole as the name.
+    pub fn parse(text: &str) -> Self {
+        let text = text.trim();
+        let mut ingredient = Ingredient {
+            text: text.to_string(),
+            quantity: None,
+            quantity_max: None,
+            unit: None,
+            name: canonical_name(text),
+        };
+
+        let (quantity, quantity_max, rest, from_article) = match parse_quantity(text) {
+            Some((quantity, quantity_max, rest)) => (quantity, quantity_max, rest, false),
+            None => match parse_article(text) {
+                Some(rest) => (1.0, None, rest, true),
+                None => return ingredient,
+            },
+        };
+        let (unit, rest) = match parse_unit(rest) {
+            Some((unit, rest)) => (Some(unit), rest),
+            // "a" or "an" only counts as a quantity in front of a unit.
+            None if from_article => return ingredient,
+            None => (None, rest),
+        };
+        let rest = rest.trim_start();
+        let rest = rest.strip_prefix("of ").unwrap_or(rest);
+        let name = canonical_name(rest);
+        if name.is_empty() {
+            return ingredient;
+        }
+
+        ingredient.quantity = Some(quantity);
+        ingredient.quantity_max = quantity_max;
+        ingredient.unit = unit.map(str::to_string);
+        ingredient.name = name;
+        ingredient
+    }
+
+    /// Multiply the quantity by `factor`. `text` is rewritten from the parts
+    /// so it shows the new amount; entries without a quantity are left alone.
+    pub fn scale(&mut self, factor: f64) {
+        let Some(quantity) = self.quantity else {
+            return;
+        };
+        self.quantity = Some(quantity * factor);
+        self.quantity_max = self.quantity_max.map(|max| max * factor);
+        self.text = self.to_string();
+    }
+
+    /// Express the quantity in `system`'s units. Entries without a quantity
+    /// or with a unit that has no conversion are left alone.
+    pub fn convert_units(&mut self, system: UnitSystem) {
+        let (Some(quantity), Some(unit)) = (self.quantity, self.unit.as_deref()) else {
+            return;
+        };
+        let Some((quantity, quantity_max, unit)) = units::convert(quantity, self.quantity_max, unit, system) else {
+            return;
+        };
+        self.quantity = Some(quantity);
+        self.quantity_max = quantity_max;
+        self.unit = Some(unit.to_string());
+        self.text = self.to_string();
+    }
+}
+
+impl fmt::Display for Ingredient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(quantity) = self.quantity {
+            write!(f, "{}", format_quantity(quantity))?;
+            if let Some(max) = self.quantity_max {
+                write!(f, "-{}", format_quantity(max))?;
+            }
+            let amount = self.quantity_max.unwrap_or(quantity);
+            match self.unit.as_deref() {
+                Some(unit) if GLUED_UNITS.contains(&unit) => write!(f, "{}", unit)?,
+                Some(unit) if amount > 1.0 => write!(f, " {}", unit_plural(unit))?,
+                Some(unit) => write!(f, " {}", unit)?,
+                None => (),
+            }
+            write!(f, " ")?;
+            if self.unit.is_none() && amount > 1.0 {
+                return match self.name.rsplit_once(' ') {
+                    Some((head, last)) => write!(f, "{} {}", head, plural(last)),
+                    None => write!(f, "{}", plural(&self.name)),
+                };
+            }
+        }
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Render a quantity the way a cook would write it: whole numbers plain,
+/// common fractions as fractions, anything else with at most two decimals.
+pub fn format_quantity(quantity: f64) -> String {
+    const FRACTIONS: &[(f64, &str)] = &[
+        (0.125, "1/8"),
+        (0.25, "1/4"),
+        (1.0 / 3.0, "1/3"),
+        (0.375, "3/8"),
+        (0.5, "1/2"),
+        (0.625, "5/8"),
+        (2.0 / 3.0, "2/3"),
+        (0.75, "3/4"),
+        (0.875, "7/8"),
+    ];
+
+    let whole = quantity.trunc();
+    let frac = quantity - whole;
+    if frac.abs() < 0.01 {
+        return format!("{}", whole as i64);
+    }
+    if whole < 10.0 {
+        for (value, name) in FRACTIONS {
+            if (frac - value).abs() < 0.01 {
+                return if whole == 0.0 {
+                    name.to_string()
+                } else {
+                    format!("{} {}", whole as i64, name)
+                };
+            }
+        }
+    }
+    let rounded = format!("{:.2}", quantity);
+    rounded.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+pub fn canonical_unit(unit: &str) -> Option<&'static str> {
+    let unit = unit.trim().trim_end_matches('.').to_lowercase();
+    UNITS
+        .iter()
+        .find(|(_, _, spellings)| spellings.contains(&unit.as_str()))
+        .map(|(canonical, _, _)| *canonical)
+}
+
+fn unit_plural(unit: &str) -> &str {
+    UNITS
+        .iter()
+        .find(|(canonical, _, _)| *canonical == unit)
+        .and_then(|(_, plural, _)| *plural)
+        .unwrap_or(unit)
+}
+
+/// Lowercased name without parenthesized notes or trailing "to taste",
+/// with a plural last word reduced to its singular.
+pub fn canonical_name(name: &str) -> String {
+    let mut plain = String::new();
+    let mut depth = 0;
+    for c in name.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = (depth - 1).max(0),
+            _ if depth == 0 => plain.extend(c.to_lowercase()),
+            _ => (),
+        }
+    }
+    let mut plain = plain.split_whitespace().collect::<Vec<_>>().join(" ");
+    for suffix in NAME_SUFFIXES {
+        if let Some(stripped) = plain.strip_suffix(suffix) {
+            plain = stripped.trim_end().to_string();
+        }
+    }
+    let plain = plain.trim_matches(|c: char| c == ',' || c.is_whitespace());
+
+    match plain.rsplit_once(' ') {
+        Some((head, last)) => format!("{} {}", head, singular(last)),
+        None => singular(plain),
+    }
+}
+
+fn singular(word: &str) -> String {
+    if let Some((_, singular)) = IRREGULAR_PLURALS.iter().find(|(plural, _)| *plural == word) {
+        singular.to_string()
+    } else if word.len() <= 3 {
+        word.to_string()
+    } else if let Some(stem) = word.strip_suffix("ies") {
+        format!("{}y", stem)
+    } else if let Some(stem) = word.strip_suffix("oes") {
+        format!("{}o", stem)
+    } else if ["ches", "shes", "sses", "xes"].iter().any(|s| word.ends_with(s)) {
+        word[..word.len() - 2].to_string()
+    } else if word.ends_with('s') && !["ss", "us", "is"].iter().any(|s| word.ends_with(s)) {
+        word[..word.len() - 1].to_string()
+    } else {
+        word.to_string()
+    }
+}
+
+fn plural(word: &str) -> String {
+    let consonant_y = word.len() > 1
+        && word.ends_with('y')
+        && !word[..word.len() - 1].ends_with(['a', 'e', 'i', 'o', 'u']);
+    if let Some((plural, _)) = IRREGULAR_PLURALS.iter().find(|(_, singular)| *singular == word) {
+        plural.to_string()
+    } else if consonant_y {
+        format!("{}ies", &word[..word.len() - 1])
+    } else if ["o", "ch", "sh", "s", "x"].iter().any(|s| word.ends_with(s)) {
+        format!("{}es", word)
+    } else {
+        format!("{}s", word)
+    }
+}
+
+/// A leading unicode fraction character such as `½`.
+fn parse_vulgar_fraction(s: &str) -> Option<(f64, &str)> {
+    let c = s.chars().next()?;
+    let value = match c {
+        '¼' => 0.25,
+        '½' => 0.5,
+        '¾' => 0.75,
+        '⅓' => 1.0 / 3.0,
+        '⅔' => 2.0 / 3.0,
+        '⅛' => 0.125,
+        _ => return None,
+    };
+    Some((value, &s[c.len_utf8()..]))
+}
+
+fn parse_digits(s: &str) -> Option<(f64, &str)> {
+    let end = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == ','))
+        .unwrap_or(s.len());
+    let digits = s[..end].trim_end_matches(['.', ',']);
+    if digits.is_empty() {
+        return None;
+    }
+    let value = digits.replace(',', ".").parse().ok()?;
+    Some((value, &s[digits.len()..]))
+}
+
+/// A single number: `2`, `1.5`, `1,5`, `1/2`, `½`, `1½` or `1 1/2`.
+fn parse_number(s: &str) -> Option<(f64, &str)> {
+    if let Some(fraction) = parse_vulgar_fraction(s) {
+        return Some(fraction);
+    }
+
+    let (value, rest) = parse_digits(s)?;
+    if let Some((frac, rest)) = parse_vulgar_fraction(rest) {
+        return Some((value + frac, rest));
+    }
+    if let Some(denominator) = rest.strip_prefix('/') {
+        let (denominator, rest) = parse_digits(denominator)?;
+        if denominator == 0.0 {
+            return None;
+        }
+        return Some((value / denominator, rest));
+    }
+    if value.fract() != 0.0 {
+        return Some((value, rest));
+    }
+
+    // Mixed numbers: "1 ½" or "1 1/2".
+    let spaced = rest.trim_start();
+    if let Some((frac, rest)) = parse_vulgar_fraction(spaced) {
+        return Some((value + frac, rest));
+    }
+    if let Some((numerator, after)) = parse_digits(spaced)
+        && let Some(denominator) = after.strip_prefix('/')
+        && let Some((denominator, rest)) = parse_digits(denominator)
+        && denominator != 0.0
+        && numerator < denominator
+    {
+        return Some((value + numerator / denominator, rest));
+    }
+    Some((value, rest))
+}
+
+/// A number or a range such as `2-3` or `2 to 3`.
+fn parse_quantity(s: &str) -> Option<(f64, Option<f64>, &str)> {
+    let (low, rest) = parse_number(s)?;
+    let after = rest.trim_start();
+    let separator = ["-", "–", "to "]
+        .iter()
+        .find_map(|sep| after.strip_prefix(sep));
+    if let Some(after) = separator
+        && let Some((high, rest)) = parse_number(after.trim_start())
+        && high > low
+    {
+        return Some((low, Some(high), rest));
+    }
+    Some((low, None, rest))
+}
+
+fn parse_article(s: &str) -> Option<&str> {
+    ["a ", "an ", "A ", "An "].iter().find_map(|article| s.strip_prefix(article))
+}
+
+fn parse_unit(s: &str) -> Option<(&'static str, &str)> {
+    let s = s.trim_start();
+    let word_end = |s: &str| {
+        s.find(|c: char| !(c.is_alphabetic() || c == '.'))
+            .unwrap_or(s.len())
+    };
+    let first = word_end(s);
+    if first == 0 {
+        return None;
+    }
+
+    // Two-word units such as "fl oz" or "fluid ounces" take precedence.
+    let after_first = &s[first..];
+    if let Some(second_start) = after_first.strip_prefix(' ') {
+        let second = word_end(second_start);
+        let end = first + 1 + second;
+        if second > 0
+            && let Some(unit) = canonical_unit(&s[..end])
+        {
+            return Some((unit, &s[end..]));
+        }
+    }
+
+    let rest = &s[first..];
+    if !(rest.is_empty() || rest.starts_with(char::is_whitespace)) {
+        return None;
+    }
+    canonical_unit(&s[..first]).map(|unit| (unit, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(text: &str) -> (Option<f64>, Option<f64>, Option<String>, String) {
+        let ingredient = Ingredient::parse(text);
+        (ingredient.quantity, ingredient.quantity_max, ingredient.unit, ingredient.name)
+    }
+
+    #[test]
+    fn parse_splits_quantity_unit_and_name() {
+        assert_eq!(parts("100ml milk"), (Some(100.0), None, Some("ml".into()), "milk".into()));
+        assert_eq!(parts("2 Tablespoons Sugar"), (Some(2.0), None, Some("tbsp".into()), "sugar".into()));
+        assert_eq!(parts("1 fl. oz rum"), (Some(1.0), None, Some("fl oz".into()), "rum".into()));
+        assert_eq!(parts("3 eggs"), (Some(3.0), None, None, "egg".into()));
+        assert_eq!(parts("a pinch of salt"), (Some(1.0), None, Some("pinch".into()), "salt".into()));
+    }
+
+    #[test]
+    fn parse_reads_fractions_and_ranges() {
+        assert_eq!(parts("1/2 tsp baking powder").0, Some(0.5));
+        assert_eq!(parts("1 1/2 cups flour").0, Some(1.5));
+        assert_eq!(parts("1½ cups flour").0, Some(1.5));
+        assert_eq!(parts("¾ cup water").0, Some(0.75));
+        assert_eq!(parts("1,5 l stock").0, Some(1.5));
+        assert_eq!(parts("2-3 cloves of garlic"), (Some(2.0), Some(3.0), Some("clove".into()), "garlic".into()));
+        assert_eq!(parts("2 to 3 tbsp oil").1, Some(3.0));
+    }
+
+    #[test]
+    fn parse_keeps_entries_without_quantity_whole() {
+        assert_eq!(parts("Salt to taste"), (None, None, None, "salt".into()));
+        assert_eq!(parts("an apple"), (None, None, None, "an apple".into()));
+        assert_eq!(parts("1/0 cup sugar"), (None, None, None, "1/0 cup sugar".into()));
+        assert_eq!(Ingredient::parse("  2 eggs ").text, "2 eggs");
+    }
+
+    #[test]
+    fn canonical_name_drops_notes_and_plurals() {
+        assert_eq!(canonical_name("Fresh Tomatoes (ripe), to taste"), "fresh tomato");
+        assert_eq!(canonical_name("cherries"), "cherry");
+        assert_eq!(canonical_name("peaches"), "peach");
+        assert_eq!(canonical_name("couscous"), "couscous");
+        assert_eq!(canonical_name("  black   pepper as needed"), "black pepper");
+    }
+
+    #[test]
+    fn singular_knows_irregular_words() {
+        assert_eq!(canonical_name("chocolate chip cookies"), "chocolate chip cookie");
+        assert_eq!(canonical_name("molasses"), "molasses");
+        assert_eq!(canonical_name("sloes"), "sloe");
+        assert_eq!(canonical_name("potatoes"), "potato");
+        assert_eq!(canonical_name("bay leaves"), "bay leaf");
+        assert_eq!(canonical_name("pies"), "pie");
+        assert_eq!(plural("cookie"), "cookies");
+        assert_eq!(plural("tomato"), "tomatoes");
+        assert_eq!(plural("avocado"), "avocados");
+        assert_eq!(plural("leaf"), "leaves");
+        assert_eq!(plural("molasses"), "molasses");
+    }
+
+    #[test]
+    fn format_quantity_writes_fractions() {
+        assert_eq!(format_quantity(2.0), "2");
+        assert_eq!(format_quantity(0.5), "1/2");
+        assert_eq!(format_quantity(0.125), "1/8");
+        assert_eq!(format_quantity(1.375), "1 3/8");
+        assert_eq!(format_quantity(1.0 / 3.0), "1/3");
+        assert_eq!(format_quantity(2.75), "2 3/4");
+        assert_eq!(format_quantity(12.5), "12.5");
+        assert_eq!(format_quantity(0.2), "0.2");
+    }
+
+    #[test]
+    fn scale_multiplies_quantity_and_rewrites_text() {
+        let mut ingredient = Ingredient::parse("1-2 tbsp sugar");
+        ingredient.scale(1.5);
+        assert_eq!((ingredient.quantity, ingredient.quantity_max), (Some(1.5), Some(3.0)));
+        assert_eq!(ingredient.text, "1 1/2-3 tbsp sugar");
+
+        let mut ingredient = Ingredient::parse("1 egg");
+        ingredient.scale(3.0);
+        assert_eq!(ingredient.text, "3 eggs");
+
+        let mut ingredient = Ingredient::parse("Salt (coarse) to taste");
+        ingredient.scale(4.0);
+        assert_eq!(ingredient.quantity, None);
+        assert_eq!(ingredient.text, "Salt (coarse) to taste");
+    }
+
+    #[test]
+    fn display_writes_parts_back() {
+        assert_eq!(Ingredient::parse("2 cup flour").to_string(), "2 cups flour");
+        assert_eq!(Ingredient::parse("250 g butter").to_string(), "250g butter");
+        assert_eq!(Ingredient::parse("2 cookies").to_string(), "2 cookies");
+    }
+}
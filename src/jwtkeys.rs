@@ -0,0 +1,279 @@
+use crate::*;
+
+use std::path::{Path, PathBuf};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::Algorithm;
+
+/// How long a key signs tokens before a new one takes over.
+const KEY_ROTATION_INTERVAL: TimeDelta = TimeDelta::days(30);
+/// How often the key directory is checked for due rotations and new keys.
+const KEY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long a new key is published before it starts signing, so that
+/// services caching the key set have it by the time tokens signed with it
+/// arrive.
+const PUBLISH_AHEAD: TimeDelta = TimeDelta::seconds(KEY_CHECK_INTERVAL.as_secs() as i64);
+/// Subdirectory of the key directory that keys no longer needed are moved
+/// to. Nothing in the key directory is ever deleted.
+const RETIRED_DIR: &str = "retired";
+
+/// One key pair from the key directory. `kid` is the file name without
+/// `.pem`.
+pub struct JwtKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub encoding: EncodingKey,
+    pub decoding: DecodingKey,
+    jwk: serde_json::Value,
+    created: DateTime<Utc>,
+}
+
+impl JwtKey {
+    /// When the key starts signing, if no newer key has by then.
+    fn signs_from(&self) -> DateTime<Utc> {
+        self.created + PUBLISH_AHEAD
+    }
+}
+
+/// The signing keys in use. The newest key old enough to have been published
+/// for [`PUBLISH_AHEAD`] signs new tokens; newer ones are only published, and
+/// older ones are kept for checking tokens they signed until those have
+/// expired.
+pub struct JwtKeys {
+    dir: PathBuf,
+    /// How long after signing a token is still accepted: its lifetime plus
+    /// the leeway allowed for clock skew.
+    token_validity: TimeDelta,
+    keys: Vec<JwtKey>,
+}
+
+/// Key pair from a PKCS#8 PEM private key, Ed25519 (EdDSA) or RSA (RS256),
+/// or a PKCS#1 PEM RSA private key.
+fn parse_key(kid: &str, pem: &str, created: DateTime<Utc>) -> Option<JwtKey> {
+    use ed25519_dalek::pkcs8::DecodePrivateKey;
+    use rsa::{pkcs1::DecodeRsaPrivateKey, traits::PublicKeyParts};
+
+    if let Ok(key) = ed25519_dalek::SigningKey::from_pkcs8_pem(pem) {
+        let x = URL_SAFE_NO_PAD.encode(key.verifying_key().as_bytes());
+        return Some(JwtKey {
+            kid: kid.to_string(),
+            algorithm: Algorithm::EdDSA,
+            encoding: EncodingKey::from_ed_pem(pem.as_bytes()).ok()?,
+            decoding: DecodingKey::from_ed_components(&x).ok()?,
+            jwk: serde_json::json!({
+                "kty": "OKP", "crv": "Ed25519", "alg": "EdDSA", "use": "sig", "kid": kid, "x": x,
+            }),
+            created,
+        });
+    }
+
+    let key = rsa::RsaPrivateKey::from_pkcs8_pem(pem)
+        .or_else(|_| rsa::RsaPrivateKey::from_pkcs1_pem(pem))
+        .ok()?;
+    let n = URL_SAFE_NO_PAD.encode(key.n().to_bytes_be());
+    let e = URL_SAFE_NO_PAD.encode(key.e().to_bytes_be());
+    Some(JwtKey {
+        kid: kid.to_string(),
+        algorithm: Algorithm::RS256,
+        encoding: EncodingKey::from_rsa_pem(pem.as_bytes()).ok()?,
+        decoding: DecodingKey::from_rsa_components(&n, &e).ok()?,
+        jwk: serde_json::json!({
+            "kty": "RSA", "alg": "RS256", "use": "sig", "kid": kid, "n": n, "e": e,
+        }),
+        created,
+    })
+}
+
+async fn write_private_key(path: &Path, pem: &str) -> std::io::Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, pem.as_bytes()).await
+}
+
+/// Write a new Ed25519 key to `dir`, named after the time it was made.
+async fn generate_key(dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    use ed25519_dalek::pkcs8::{EncodePrivateKey, spki::der::pem::LineEnding};
+
+    let key = ed25519_dalek::SigningKey::generate(&mut rand::rngs::OsRng);
+    let pem = key.to_pkcs8_pem(LineEnding::LF)?;
+    let kid = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    write_private_key(&dir.join(format!("{}.pem", kid)), &pem).await?;
+    log::info!("generated jwt signing key {}", kid);
+    Ok(())
+}
+
+impl JwtKeys {
+    /// Read every `*.pem` key in `dir`, newest first by modification time.
+    async fn read(dir: &Path, token_validity: TimeDelta) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut keys = Vec::new();
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "pem") {
+                continue;
+            }
+            let Some(kid) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let created = entry.metadata().await?.modified()?.into();
+            let pem = tokio::fs::read_to_string(&path).await?;
+            match parse_key(kid, &pem, created) {
+                Some(key) => keys.push(key),
+                None => log::warn!("jwt key {}: not an Ed25519 or RSA private key", path.display()),
+            }
+        }
+        keys.sort_by(|a, b| b.created.cmp(&a.created).then(b.kid.cmp(&a.kid)));
+        Ok(Self { dir: dir.to_path_buf(), token_validity, keys })
+    }
+
+    /// Load the keys in `dir`, first adding a new key if there is none or
+    /// the newest is due to be replaced within [`PUBLISH_AHEAD`], and
+    /// retiring keys whose tokens, accepted for `token_validity`, can no
+    /// longer be used.
+    pub async fn load(dir: &Path, token_validity: TimeDelta) -> Result<Self, Box<dyn std::error::Error>> {
+        tokio::fs::create_dir_all(dir).await?;
+        let mut keys = Self::read(dir, token_validity).await?;
+        let due = keys
+            .keys
+            .first()
+            .is_none_or(|newest| newest.created + KEY_ROTATION_INTERVAL - PUBLISH_AHEAD <= Utc::now());
+        if due {
+            generate_key(dir).await?;
+            keys = Self::read(dir, token_validity).await?;
+        }
+        keys.prune().await;
+        if keys.keys.is_empty() {
+            return Err(format!("no usable jwt keys in {}", dir.display()).into());
+        }
+        Ok(keys)
+    }
+
+    /// Reload from the same directory, e.g. to pick up a rotation.
+    pub async fn reload(&self) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::load(&self.dir, self.token_validity).await
+    }
+
+    // A key stops signing when the next one starts, so once the longest
+    // lived token it could have signed is past its expiry and the leeway
+    // after it, the key is no longer needed. It is moved aside rather than
+    // deleted, since it may be one an operator put there.
+    async fn prune(&mut self) {
+        let now = Utc::now();
+        let mut keep = self.signing() + 1;
+        while keep < self.keys.len()
+            && self.keys[keep - 1].signs_from() + self.token_validity > now
+        {
+            keep += 1;
+        }
+        if keep == self.keys.len() {
+            return;
+        }
+        let retired = self.dir.join(RETIRED_DIR);
+        if let Err(e) = tokio::fs::create_dir_all(&retired).await {
+            log::warn!("could not make {}: {}", retired.display(), e);
+            return;
+        }
+        for key in self.keys.drain(keep..) {
+            let name = format!("{}.pem", key.kid);
+            match tokio::fs::rename(self.dir.join(&name), retired.join(&name)).await {
+                Ok(()) => log::info!("retired jwt signing key {}", key.kid),
+                Err(e) => log::warn!("could not retire jwt key {}: {}", key.kid, e),
+            }
+        }
+    }
+
+    /// Index of the key that signs new tokens: the newest that has been
+    /// published long enough, or the oldest if none has, as when the first
+    /// key has just been made.
+    fn signing(&self) -> usize {
+        let now = Utc::now();
+        self.keys
+            .iter()
+            .position(|key| key.signs_from() <= now)
+            .unwrap_or(self.keys.len().saturating_sub(1))
+    }
+
+    /// The key that signs new tokens.
+    pub fn current(&self) -> &JwtKey {
+        &self.keys[self.signing()]
+    }
+
+    pub fn get(&self, kid: &str) -> Option<&JwtKey> {
+        self.keys.iter().find(|key| key.kid == kid)
+    }
+
+    /// Public halves of all keys, as a JSON Web Key Set.
+    pub fn jwks(&self) -> serde_json::Value {
+        let keys: Vec<&serde_json::Value> = self.keys.iter().map(|key| &key.jwk).collect();
+        serde_json::json!({ "keys": keys })
+    }
+}
+
+pub async fn make_jwt_keys(token_validity: TimeDelta) -> Result<JwtKeys, Box<dyn std::error::Error>> {
+    let dir = std::env::var("JWT_KEYDIR").unwrap_or_else(|_| "secrets/jwt_keys".to_owned());
+    JwtKeys::load(Path::new(&dir), token_validity).await
+}
+
+/// Check the key directory every [`KEY_CHECK_INTERVAL`], rotating the
+/// signing key when it is due and picking up keys added by hand.
+pub async fn rotate_jwt_keys(state: SharedAppState) {
+    let mut interval = tokio::time::interval(KEY_CHECK_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let reloaded = state.read().await.jwt_keys.reload().await.map_err(|e| e.to_string());
+        match reloaded {
+            Ok(keys) => state.write().await.jwt_keys = keys,
+            Err(e) => log::warn!("jwt key reload failed: {}", e),
+        }
+    }
+}
+
+pub async fn get_jwks(State(appstate): State<SharedAppState>) -> axum::response::Response {
+    let appstate = appstate.read().await;
+    Json(appstate.jwt_keys.jwks()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{add_key, key_dir};
+
+    #[tokio::test]
+    async fn load_publishes_next_key_before_it_signs() {
+        let dir = key_dir();
+        add_key(&dir, "old", KEY_ROTATION_INTERVAL - PUBLISH_AHEAD / 2);
+        let keys = JwtKeys::load(&dir, TimeDelta::minutes(16)).await.unwrap();
+        assert_eq!(keys.keys.len(), 2);
+        assert_eq!(keys.current().kid, "old");
+        assert_eq!(keys.jwks()["keys"].as_array().unwrap().len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn load_moves_unneeded_keys_aside() {
+        let dir = key_dir();
+        add_key(&dir, "restored", TimeDelta::days(40));
+        add_key(&dir, "current", TimeDelta::days(10));
+        let keys = JwtKeys::load(&dir, TimeDelta::minutes(16)).await.unwrap();
+        assert_eq!(keys.keys.len(), 1);
+        assert_eq!(keys.current().kid, "current");
+        assert!(keys.get("restored").is_none());
+        assert!(dir.join(RETIRED_DIR).join("restored.pem").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn load_keeps_keys_whose_tokens_are_still_accepted() {
+        let dir = key_dir();
+        add_key(&dir, "previous", TimeDelta::days(31));
+        add_key(&dir, "current", TimeDelta::days(1) + PUBLISH_AHEAD);
+        let keys = JwtKeys::load(&dir, TimeDelta::days(2)).await.unwrap();
+        assert_eq!(keys.current().kid, "current");
+        assert!(keys.get("previous").is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
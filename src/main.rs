@@ -1,13 +1,30 @@
+mod apikey;
+mod audit;
 mod error;
+mod extract;
+mod ingredient;
 mod recipe;
+mod search;
+mod session;
 mod templates;
+mod throttle;
+mod token;
+mod totp;
+mod units;
+mod user;
 mod web;
 mod api;
 mod authjwt;
+mod jwtkeys;
+mod oidc;
+#[cfg(test)]
+mod testing;
 
 use error::*;
+use ingredient::*;
 use recipe::*;
 use templates::*;
+use units::UnitSystem;
 
 extern crate log;
 extern crate mime;
@@ -15,13 +32,13 @@ extern crate mime;
 use axum::{
     self,
     RequestPartsExt,
-    extract::{Path, Query, State, Json},
+    extract::{ConnectInfo, Query, State, Json},
     http::{self, StatusCode},
     response::{self, IntoResponse},
     routing,
 };
 use axum_extra::{
-    headers::{authorization::Bearer, Authorization},
+    headers::{self, authorization::Bearer, Authorization, HeaderMapExt},
     TypedHeader,
 };
 use chrono::{prelude::*, TimeDelta};
@@ -29,9 +46,9 @@ use clap::Parser;
 extern crate fastrand;
 use jsonwebtoken::{EncodingKey, DecodingKey};
 use serde::{Serialize, Deserialize};
-use sqlx::{Row, SqlitePool, migrate::MigrateDatabase, sqlite};
+use sqlx::{SqlitePool, migrate::MigrateDatabase, sqlite};
 use tokio::{net, signal, sync::RwLock, time::Duration};
-use tower_http::{services, trace};
+use tower_http::{request_id, services, trace};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use utoipa::{OpenApi, ToSchema};
 use utoipa_axum::{router::OpenApiRouter, routes};
@@ -40,6 +57,8 @@ use utoipa_redoc::{Redoc, Servable};
 use utoipa_swagger_ui::SwaggerUi;
 
 use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::Arc;
 
 #[derive(Parser)]
@@ -52,35 +71,58 @@ struct Args {
     ip: String,
     #[arg(short, long, default_value = "3000")]
     port: u16,
+    /// Make the account registered under this email an admin.
+    #[arg(long, name = "admin-email")]
+    admin_email: Option<String>,
+    /// Address of a reverse proxy in front of the server, whose
+    /// X-Forwarded-For header is trusted to name the client. May be repeated.
+    #[arg(long = "trusted-proxy", name = "trusted-proxy")]
+    trusted_proxies: Vec<std::net::IpAddr>,
 }
 
 struct AppState {
     db: SqlitePool,
-    jwt_keys: authjwt::JwtKeys,
-    reg_key: String,
+    token_config: authjwt::TokenConfig,
+    jwt_keys: jwtkeys::JwtKeys,
+    oidc: Option<oidc::Provider>,
+    trusted_proxies: Vec<std::net::IpAddr>,
     current_recipe: Recipe,
 }
 
 type SharedAppState = Arc<RwLock<AppState>>;
 
 impl AppState {
-    pub fn new(db: SqlitePool, jwt_keys: authjwt::JwtKeys, reg_key: String) -> Self {
+    pub fn new(
+        db: SqlitePool,
+        token_config: authjwt::TokenConfig,
+        jwt_keys: jwtkeys::JwtKeys,
+        oidc: Option<oidc::Provider>,
+        trusted_proxies: Vec<std::net::IpAddr>,
+    ) -> Self {
         let current_recipe = Recipe {
             id: 0,
             title: "thing".to_string(),
             category: "thingies".to_string(),
             preparation: "notreal".to_string(),
+            servings: None,
+            author_id: None,
+            author: None,
+            created_at: None,
+            updated_at: None,
+            version: 1,
         };
         Self {
             db,
+            token_config,
             jwt_keys,
-            reg_key,
+            oidc,
+            trusted_proxies,
             current_recipe,
         }
     }
 }
 
-fn get_db_uri(db_uri: Option<&str>) -> Cow<str> {
+fn get_db_uri(db_uri: Option<&str>) -> Cow<'_, str> {
     if let Some(db_uri) = db_uri {
         db_uri.into()
     } else if let Ok(db_uri) = std::env::var("DB_URI") {
@@ -158,58 +200,58 @@ async fn serve() -> Result<(), Box<dyn std::error::Error>> {
         sqlite::Sqlite::create_database(&db_uri).await?
     }
 
-    let db = SqlitePool::connect(&db_uri).await?;
+    let db_options = sqlite::SqliteConnectOptions::from_str(&db_uri)?.foreign_keys(true);
+    let db = SqlitePool::connect_with(db_options).await?;
     sqlx::migrate!().run(&db).await?;
+    recipe::parse_legacy_ingredients(&db).await?;
     if let Some(path) = args.init_from {
         let recipes = read_recipes(path)?;
-        'next_recipe: for rr in recipes {
-            let mut rtx = db.begin().await?;
-            let (r, is) = rr.to_recipe();
-            let recipe_insert = sqlx::query!(
-                "INSERT INTO recipes (id, title, category, preparation) VALUES ($1, $2, $3, $4);",
-                r.id,
-                r.title,
-                r.category,
-                r.preparation,
-            )
-            .execute(&mut *rtx)
-            .await;
-            if let Err(e) = recipe_insert {
-                eprintln!("error: recipe insert: {}: {}", r.id, e);
-                rtx.rollback().await?;
-                continue;
-            };
-            for i in is {
-                let ingredient_insert = 
-                    sqlx::query!("INSERT INTO ingredients (recipe_id, ingredient_amount) VALUES ($1, $2);", r.id, i,)
-                        .execute(&mut *rtx)
-                        .await;
-                if let Err(e) = ingredient_insert {
-                    eprintln!("error: ingredient insert: {} {}: {}", r.id, i, e);
-                    rtx.rollback().await?;
-                    continue 'next_recipe;
-                };
+        let mut conn = db.acquire().await?;
+        for rr in recipes {
+            let title = rr.title().to_string();
+            if let Err(e) = recipe::add(&mut conn, rr, None).await {
+                eprintln!("error: recipe insert: {}: {}", title, e);
             }
-            rtx.commit().await?;
         }
     }
+    if let Some(email) = args.admin_email {
+        let mut tx = db.begin().await?;
+        match user::promote_admin(&mut tx, &email).await? {
+            None => log::warn!("admin email {} has no account; register it and restart", email),
+            Some((_, user::Role::Admin)) => (),
+            Some((user_id, before)) => {
+                log::info!("user {} is now an admin", user_id);
+                let event = audit::Event::new(audit::Action::RoleChange, None)
+                    .target(user_id)
+                    .diff(serde_json::json!({ "role": { "before": before, "after": user::Role::Admin } }));
+                audit::write(&mut *tx, event).await?;
+            }
+        }
+        tx.commit().await?;
+    }
 
-    let jwt_keys = authjwt::make_jwt_keys().await.unwrap_or_else(|_| {
+    let token_config = authjwt::make_token_config().unwrap_or_else(|e| {
+        tracing::error!("jwt config");
+        eprintln!("jwt config err: {}", e);
+        std::process::exit(1);
+    });
+
+    let token_validity = token_config.lifetime + TimeDelta::seconds(token_config.leeway as i64);
+    let jwt_keys = jwtkeys::make_jwt_keys(token_validity).await.unwrap_or_else(|e| {
         tracing::error!("jwt keys");
-        eprintln!("jwt keys err");
+        eprintln!("jwt keys err: {}", e);
         std::process::exit(1);
     });
 
-    let reg_key = authjwt::read_secret("REG_PASSWORD", "secrets/password.txt")
-        .await
-        .unwrap_or_else(|_| {
-            tracing::error!("reg password");
-            eprintln!("reg password");
-            std::process::exit(1);
-        });
+    let oidc = oidc::make_provider().await.unwrap_or_else(|e| {
+        tracing::error!("oidc provider");
+        eprintln!("oidc provider err: {}", e);
+        std::process::exit(1);
+    });
 
-    let app_state = AppState::new(db, jwt_keys, reg_key);
+    let app_state = AppState::new(db, token_config, jwt_keys, oidc, args.trusted_proxies);
     let state = Arc::new(RwLock::new(app_state));
+    tokio::spawn(jwtkeys::rotate_jwt_keys(state.clone()));
 
     // https://carlosmv.hashnode.dev/adding-logging-and-tracing-to-an-axum-app-rust
     let trace_layer = trace::TraceLayer::new_for_http()
@@ -217,7 +259,13 @@ async fn serve() -> Result<(), Box<dyn std::error::Error>> {
         .on_response(trace::DefaultOnResponse::new().level(tracing::Level::INFO));
 
     let cors = tower_http::cors::CorsLayer::new()
-        .allow_methods([http::Method::GET, http::Method::POST])
+        .allow_methods([
+            http::Method::GET,
+            http::Method::POST,
+            http::Method::PUT,
+            http::Method::PATCH,
+            http::Method::DELETE,
+        ])
         .allow_origin(tower_http::cors::Any);
 
     async fn handler_404() -> axum::response::Response {
@@ -237,6 +285,10 @@ async fn serve() -> Result<(), Box<dyn std::error::Error>> {
 
     let app = axum::Router::new()
         .route("/", routing::get(web::get_recipe))
+        .route("/login", routing::get(web::get_login).post(web::post_login))
+        .route("/logout", routing::post(web::post_logout))
+        .route("/add", routing::get(web::get_add_recipe).post(web::post_add_recipe))
+        .route("/.well-known/jwks.json", routing::get(jwtkeys::get_jwks))
         .route_service(
             "/style.css",
             services::ServeFile::new_with_mime("assets/static/style.css", &mime::TEXT_CSS_UTF_8,),
@@ -252,12 +304,15 @@ async fn serve() -> Result<(), Box<dyn std::error::Error>> {
         .fallback(handler_404)
         .layer(cors)
         .layer(trace_layer)
+        .layer(request_id::PropagateRequestIdLayer::x_request_id())
+        .layer(request_id::SetRequestIdLayer::x_request_id(request_id::MakeRequestUuid))
+        .layer(axum::middleware::map_request(audit::drop_client_request_id))
         .with_state(state);
 
     let endpoint = format!("{}:{}", args.ip, args.port);
     let listener = net::TcpListener::bind(&endpoint).await?;
     log::info!("started: listening on {}", endpoint);
-    axum::serve(listener, app)
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await?;
     Ok(())
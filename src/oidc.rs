@@ -0,0 +1,668 @@
+use crate::*;
+
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{Algorithm, jwk::JwkSet};
+
+/// How long a login may spend at the identity provider before coming back.
+const LOGIN_LIFETIME: TimeDelta = TimeDelta::minutes(10);
+/// Cookie holding a hash of the state of the login this browser started.
+/// The callback is refused without it, so a provider URL handed to someone
+/// else cannot sign them in to, or link their identity to, another account.
+const STATE_COOKIE: &str = "rs_oidc_state";
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Provider metadata from discovery; only the parts used here.
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+/// An OpenID Connect identity provider that accounts can sign in through,
+/// using the authorization code flow with PKCE.
+pub struct Provider {
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_url: String,
+    /// Let accounts with an authenticator sign in here, counting on the
+    /// provider to ask for a second factor of its own.
+    trust_mfa: bool,
+    discovery: Discovery,
+    http: reqwest::Client,
+}
+
+/// Query of the provider's redirect back to us.
+#[derive(Debug, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Callback {
+    /// Authorization code to trade for tokens.
+    code: Option<String>,
+    /// Names the login started at `/oidc/login`.
+    state: String,
+    /// Set instead of `code` when the provider refused the login.
+    error: Option<String>,
+}
+
+/// Where to send the browser to link an identity to the caller's account.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct LinkStart {
+    #[schema(example = "https://idp.example.org/authorize?response_type=code&client_id=recipe-server")]
+    authorization_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// ID token claims used to find or create the local account. `iss`, `aud`
+/// and `exp` are checked by the decoder.
+#[derive(Debug, Deserialize)]
+struct IdClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    name: Option<String>,
+}
+
+/// The identity provider configured by `OIDC_ISSUER`, `OIDC_CLIENT_ID`,
+/// `OIDC_REDIRECT_URL` and, for confidential clients, `OIDC_CLIENT_SECRET`,
+/// or `None` if `OIDC_ISSUER` is unset. `OIDC_TRUST_MFA=true` lets accounts
+/// with an authenticator sign in through it. Fails if discovery does.
+pub async fn make_provider() -> Result<Option<Provider>, Box<dyn std::error::Error>> {
+    let Ok(issuer) = std::env::var("OIDC_ISSUER") else {
+        return Ok(None);
+    };
+    let client_id = std::env::var("OIDC_CLIENT_ID").map_err(|_| "OIDC_CLIENT_ID not set")?;
+    let redirect_url = std::env::var("OIDC_REDIRECT_URL").map_err(|_| "OIDC_REDIRECT_URL not set")?;
+    let client_secret = std::env::var("OIDC_CLIENT_SECRET").ok();
+    let trust_mfa = match std::env::var("OIDC_TRUST_MFA").as_deref() {
+        Err(_) | Ok("false") => false,
+        Ok("true") => true,
+        Ok(value) => return Err(format!("OIDC_TRUST_MFA: not true or false: {}", value).into()),
+    };
+    let mut provider = Provider::discover(&issuer, client_id, client_secret, redirect_url).await?;
+    provider.trust_mfa = trust_mfa;
+    log::info!("oidc login through {}", issuer);
+    Ok(Some(provider))
+}
+
+fn provider_error(e: impl std::fmt::Display) -> authjwt::AuthError {
+    log::warn!("oidc provider request failed: {}", e);
+    authjwt::AuthError::IdentityProvider
+}
+
+impl Provider {
+    /// Look up the endpoints of the provider at `issuer`.
+    async fn discover(
+        issuer: &str,
+        client_id: String,
+        client_secret: Option<String>,
+        redirect_url: String,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let http = reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?;
+        let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+        let discovery: Discovery = http.get(&url).send().await?.error_for_status()?.json().await?;
+        if discovery.issuer != issuer {
+            return Err(format!("oidc discovery: issuer is {}, expected {}", discovery.issuer, issuer).into());
+        }
+        Ok(Provider { client_id, client_secret, redirect_url, trust_mfa: false, discovery, http })
+    }
+
+    /// The state cookie, scoped to the callback's path.
+    fn state_cookie(&self, value: String) -> Cookie<'static> {
+        let path = reqwest::Url::parse(&self.redirect_url)
+            .map(|url| url.path().to_string())
+            .unwrap_or_else(|_| "/".to_string());
+        Cookie::build((STATE_COOKIE, value))
+            .path(path)
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Lax)
+            .build()
+    }
+
+    /// Start a login: remember a new PKCE verifier and nonce under a new
+    /// state, and return the provider URL to send the browser to, with `jar`
+    /// holding the state cookie that browser must bring back. With
+    /// `link_user_id`, the identity is linked to that account when the login
+    /// comes back.
+    pub async fn start_login(
+        &self,
+        db: &SqlitePool,
+        jar: CookieJar,
+        link_user_id: Option<i64>,
+    ) -> Result<(String, CookieJar), authjwt::AuthError> {
+        use sha2::{Digest, Sha256};
+
+        let state = authjwt::random_token(16);
+        let code_verifier = authjwt::random_token(32);
+        let nonce = authjwt::random_token(16);
+        let now = Utc::now();
+        let expires_at = (now + LOGIN_LIFETIME).timestamp();
+        add_login(db, &state, &code_verifier, &nonce, link_user_id, expires_at, now.timestamp())
+            .await
+            .map_err(authjwt::storage_error)?;
+
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+        let mut url = reqwest::Url::parse(&self.discovery.authorization_endpoint).map_err(provider_error)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", "openid email profile")
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+        let jar = jar.add(self.state_cookie(authjwt::hash_token(&state)));
+        Ok((url.into(), jar))
+    }
+
+    /// Trade the code from `callback` for an ID token and return its checked
+    /// claims, with the account the login links to if any. Each state can be
+    /// used once, and only by the browser whose `jar` has its cookie.
+    async fn finish_login(
+        &self,
+        db: &SqlitePool,
+        jar: &CookieJar,
+        callback: &Callback,
+    ) -> Result<(IdClaims, Option<i64>), authjwt::AuthError> {
+        let started_here = jar
+            .get(STATE_COOKIE)
+            .is_some_and(|cookie| cookie.value() == authjwt::hash_token(&callback.state));
+        if !started_here {
+            log::warn!("oidc callback from a browser that did not start the login");
+            return Err(authjwt::AuthError::OidcLogin);
+        }
+        let now = Utc::now().timestamp();
+        let login = take_login(db, &callback.state)
+            .await
+            .map_err(authjwt::storage_error)?
+            .filter(|login| login.expires_at >= now)
+            .ok_or(authjwt::AuthError::OidcLogin)?;
+        if let Some(error) = &callback.error {
+            log::warn!("oidc login refused by provider: {}", error);
+            return Err(authjwt::AuthError::OidcLogin);
+        }
+        let code = callback.code.as_deref().ok_or(authjwt::AuthError::OidcLogin)?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_url),
+            ("client_id", &self.client_id),
+            ("code_verifier", &login.code_verifier),
+        ];
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let tokens: TokenResponse = self
+            .http
+            .post(&self.discovery.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(provider_error)?
+            .json()
+            .await
+            .map_err(provider_error)?;
+
+        let claims = self.check_id_token(&tokens.id_token).await?;
+        if claims.nonce.as_deref() != Some(login.nonce.as_str()) {
+            log::warn!("oidc id token nonce mismatch");
+            return Err(authjwt::AuthError::OidcLogin);
+        }
+        Ok((claims, login.link_user_id))
+    }
+
+    // The provider's keys are fetched for every login rather than cached, so
+    // its key rotations need no handling here; logins are rare enough.
+    async fn check_id_token(&self, id_token: &str) -> Result<IdClaims, authjwt::AuthError> {
+        use jsonwebtoken::{DecodingKey, Validation, decode, decode_header};
+
+        let header = decode_header(id_token).map_err(|_| authjwt::AuthError::OidcLogin)?;
+        // Only signatures by the provider's published keys count, never a
+        // shared secret.
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(authjwt::AuthError::OidcLogin);
+        }
+        let jwks: JwkSet = self
+            .http
+            .get(&self.discovery.jwks_uri)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(provider_error)?
+            .json()
+            .await
+            .map_err(provider_error)?;
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None if jwks.keys.len() == 1 => jwks.keys.first(),
+            None => None,
+        }
+        .ok_or(authjwt::AuthError::OidcLogin)?;
+        let key = DecodingKey::from_jwk(jwk).map_err(|_| authjwt::AuthError::OidcLogin)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.discovery.issuer]);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        decode::<IdClaims>(id_token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| {
+                log::warn!("oidc id token rejected: {}", e);
+                authjwt::AuthError::OidcLogin
+            })
+    }
+}
+
+/// The local account for an identity: the one it signed in as before, else
+/// the account `link_user_id` that started the login, else a passwordless
+/// account with the same verified email, else a new account. An account
+/// with a password is never taken over by email; its owner has to sign in
+/// and link the identity.
+async fn local_user(
+    db: &SqlitePool,
+    issuer: &str,
+    claims: &IdClaims,
+    link_user_id: Option<i64>,
+    request_id: Option<&str>,
+) -> Result<(i64, user::Role), authjwt::AuthError> {
+    let role = async |user_id| {
+        user::get_role(db, user_id)
+            .await
+            .map_err(authjwt::storage_error)?
+            .ok_or(authjwt::AuthError::Storage)
+    };
+    if let Some(user_id) = get_identity(db, issuer, &claims.sub).await.map_err(authjwt::storage_error)? {
+        if link_user_id.is_some_and(|link_user_id| link_user_id != user_id) {
+            return Err(authjwt::AuthError::IdentityLinked);
+        }
+        return Ok((user_id, role(user_id).await?));
+    }
+    if let Some(user_id) = link_user_id {
+        let role = role(user_id).await?;
+        add_identity(db, issuer, &claims.sub, user_id)
+            .await
+            .map_err(authjwt::storage_error)?;
+        return Ok((user_id, role));
+    }
+
+    let email = claims.email.as_deref().ok_or(authjwt::AuthError::OidcLogin)?;
+    let existing = match claims.email_verified {
+        true => user::get_by_email(db, email).await.map_err(authjwt::storage_error)?,
+        false => None,
+    };
+    let mut tx = db.begin().await.map_err(authjwt::storage_error)?;
+    let (user_id, role) = match existing {
+        Some(user) if user.password_hash.is_empty() => (user.id, user.role),
+        Some(_) => return Err(authjwt::AuthError::AccountExists),
+        None => {
+            let full_name = claims.name.as_deref().unwrap_or(email);
+            let (user_id, role) = match user::add(&mut *tx, full_name, email, "").await {
+                Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                    return Err(authjwt::AuthError::AccountExists);
+                }
+                Err(e) => return Err(authjwt::storage_error(e)),
+                Ok(added) => added,
+            };
+            let event = audit::Event::new(audit::Action::Register, request_id)
+                .actor(Some(user_id))
+                .target(user_id)
+                .diff(serde_json::json!({ "role": { "before": null, "after": role } }));
+            audit::write(&mut *tx, event).await.map_err(authjwt::storage_error)?;
+            (user_id, role)
+        }
+    };
+    add_identity(&mut *tx, issuer, &claims.sub, user_id)
+        .await
+        .map_err(authjwt::storage_error)?;
+    tx.commit().await.map_err(authjwt::storage_error)?;
+    Ok((user_id, role))
+}
+
+/// Finish a login that came back from the provider to the browser with
+/// `jar` and return tokens for the local account. The provider cannot vouch
+/// for an authenticator it never saw, so unless it is trusted to ask for a
+/// second factor itself, accounts with one must sign in with password and
+/// code; linking is fine, as that started from such a sign in.
+pub async fn login(
+    appstate: &AppState,
+    provider: &Provider,
+    jar: &CookieJar,
+    callback: &Callback,
+    request_id: Option<&str>,
+) -> Result<authjwt::AuthBody, authjwt::AuthError> {
+    let (claims, link_user_id) = provider.finish_login(&appstate.db, jar, callback).await?;
+    let issuer = &provider.discovery.issuer;
+    let (user_id, role) = local_user(&appstate.db, issuer, &claims, link_user_id, request_id).await?;
+    if link_user_id.is_none() && !provider.trust_mfa && totp::enabled(&appstate.db, user_id).await? {
+        return Err(authjwt::AuthError::OidcTotp);
+    }
+    let event = audit::Event::new(audit::Action::Login, request_id)
+        .actor(Some(user_id))
+        .target(user_id);
+    audit::record(&appstate.db, event).await;
+    authjwt::issue_tokens(appstate, user_id, role, None).await
+}
+
+/// Start linking an identity at the provider to the caller's account. The
+/// state cookie goes to the caller's browser with the answer, so the link
+/// can only be finished there: a provider URL passed on to someone else
+/// cannot link their identity to the caller's account.
+pub async fn start_link(
+    appstate: &AppState,
+    provider: &Provider,
+    jar: CookieJar,
+    claims: &authjwt::Claims,
+) -> Result<(LinkStart, CookieJar), authjwt::AuthError> {
+    claims.require_session()?;
+    let user_id = claims.user_id()?;
+    let (authorization_url, jar) = provider.start_login(&appstate.db, jar, Some(user_id)).await?;
+    Ok((LinkStart { authorization_url }, jar))
+}
+
+/// `jar` without the state cookie, once the login it was for is over.
+pub fn end_login(provider: &Provider, jar: CookieJar) -> CookieJar {
+    jar.remove(provider.state_cookie(String::new()))
+}
+
+struct PendingLogin {
+    code_verifier: String,
+    nonce: String,
+    link_user_id: Option<i64>,
+    expires_at: i64,
+}
+
+/// Store a login started now, clearing out ones that were never finished.
+async fn add_login(
+    db: &SqlitePool,
+    state: &str,
+    code_verifier: &str,
+    nonce: &str,
+    link_user_id: Option<i64>,
+    expires_at: i64,
+    now: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM oidc_logins WHERE expires_at < $1;", now)
+        .execute(db)
+        .await?;
+    sqlx::query!(
+        r#"INSERT INTO oidc_logins (state, code_verifier, nonce, link_user_id, expires_at)
+        VALUES ($1, $2, $3, $4, $5);"#,
+        state,
+        code_verifier,
+        nonce,
+        link_user_id,
+        expires_at,
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Remove and return the login started under `state`.
+async fn take_login(db: &SqlitePool, state: &str) -> Result<Option<PendingLogin>, sqlx::Error> {
+    sqlx::query_as!(
+        PendingLogin,
+        r#"DELETE FROM oidc_logins WHERE state = $1
+        RETURNING code_verifier, nonce, link_user_id, expires_at;"#,
+        state,
+    )
+    .fetch_optional(db)
+    .await
+}
+
+async fn get_identity(db: &SqlitePool, issuer: &str, subject: &str) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar!(
+        "SELECT user_id FROM oidc_identities WHERE issuer = $1 AND subject = $2;",
+        issuer,
+        subject,
+    )
+    .fetch_optional(db)
+    .await
+}
+
+async fn add_identity(db: impl sqlx::SqliteExecutor<'_>, issuer: &str, subject: &str, user_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO oidc_identities (issuer, subject, user_id) VALUES ($1, $2, $3);",
+        issuer,
+        subject,
+        user_id,
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_appstate, test_db};
+
+    use jsonwebtoken::{Header, encode};
+    use std::sync::Mutex;
+
+    /// A stand-in identity provider on a local port, serving discovery, its
+    /// key set, and a token endpoint that hands out whatever ID token the
+    /// test put in `id_token`.
+    struct StubProvider {
+        issuer: String,
+        key: EncodingKey,
+        id_token: Arc<Mutex<String>>,
+    }
+
+    async fn stub_provider() -> StubProvider {
+        use ed25519_dalek::pkcs8::{EncodePrivateKey, spki::der::pem::LineEnding};
+
+        let signing_key = ed25519_dalek::SigningKey::generate(&mut rand::rngs::OsRng);
+        let pem = signing_key.to_pkcs8_pem(LineEnding::LF).unwrap();
+        let key = EncodingKey::from_ed_pem(pem.as_bytes()).unwrap();
+        let jwks = serde_json::json!({ "keys": [{
+            "kty": "OKP", "crv": "Ed25519", "alg": "EdDSA", "use": "sig", "kid": "stub",
+            "x": URL_SAFE_NO_PAD.encode(signing_key.verifying_key().as_bytes()),
+        }]});
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let discovery = serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        });
+        let id_token = Arc::new(Mutex::new(String::new()));
+        let token = id_token.clone();
+        let app = axum::Router::new()
+            .route("/.well-known/openid-configuration", routing::get(move || async move { Json(discovery) }))
+            .route("/jwks", routing::get(move || async move { Json(jwks) }))
+            .route(
+                "/token",
+                routing::post(move || {
+                    let id_token = token.lock().unwrap().clone();
+                    async move { Json(serde_json::json!({ "id_token": id_token, "token_type": "Bearer" })) }
+                }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        StubProvider { issuer, key, id_token }
+    }
+
+    async fn provider(stub: &StubProvider) -> Provider {
+        let redirect_url = "http://localhost:3000/api/v1/oidc/callback".to_string();
+        Provider::discover(&stub.issuer, "recipe-server".to_string(), None, redirect_url)
+            .await
+            .unwrap()
+    }
+
+    /// Send a login through the stub, with the stub signing an ID token with
+    /// `claims` plus whatever standard claims they leave out, and return its
+    /// callback and the browser's cookies.
+    async fn visit_provider(
+        stub: &StubProvider,
+        provider: &Provider,
+        db: &SqlitePool,
+        mut claims: serde_json::Value,
+        link_user_id: Option<i64>,
+    ) -> Result<(Callback, CookieJar), authjwt::AuthError> {
+        let (url, jar) = provider.start_login(db, CookieJar::new(), link_user_id).await?;
+        let url = reqwest::Url::parse(&url).unwrap();
+        let param = |name: &str| url.query_pairs().find(|(key, _)| key == name).unwrap().1.into_owned();
+        let defaults = serde_json::json!({
+            "iss": stub.issuer,
+            "aud": "recipe-server",
+            "exp": Utc::now().timestamp() + 300,
+            "nonce": param("nonce"),
+        });
+        for (name, value) in defaults.as_object().unwrap() {
+            claims.as_object_mut().unwrap().entry(name).or_insert(value.clone());
+        }
+        let header = Header { kid: Some("stub".to_string()), ..Header::new(Algorithm::EdDSA) };
+        *stub.id_token.lock().unwrap() = encode(&header, &claims, &stub.key).unwrap();
+
+        let callback = Callback { code: Some("code".to_string()), state: param("state"), error: None };
+        Ok((callback, jar))
+    }
+
+    /// Send a login through the stub and back, returning the local account.
+    async fn sign_in(
+        stub: &StubProvider,
+        provider: &Provider,
+        db: &SqlitePool,
+        claims: serde_json::Value,
+        link_user_id: Option<i64>,
+    ) -> Result<(i64, user::Role), authjwt::AuthError> {
+        let (callback, jar) = visit_provider(stub, provider, db, claims, link_user_id).await?;
+        let (claims, link_user_id) = provider.finish_login(db, &jar, &callback).await?;
+        local_user(db, &provider.discovery.issuer, &claims, link_user_id, None).await
+    }
+
+    fn identity(sub: &str, email: &str) -> serde_json::Value {
+        serde_json::json!({ "sub": sub, "email": email, "email_verified": true, "name": "Jo Cook" })
+    }
+
+    #[tokio::test]
+    async fn new_identity_gets_an_account_it_keeps() {
+        let (stub, db) = (stub_provider().await, test_db().await);
+        let provider = provider(&stub).await;
+        let (user_id, role) = sign_in(&stub, &provider, &db, identity("jo", "jo@example.org"), None)
+            .await
+            .unwrap();
+        assert_eq!(role, user::Role::Reader);
+        let again = sign_in(&stub, &provider, &db, identity("jo", "other@example.org"), None).await;
+        assert_eq!(again.unwrap().0, user_id);
+    }
+
+    #[tokio::test]
+    async fn verified_email_does_not_take_over_password_account() {
+        let (stub, db) = (stub_provider().await, test_db().await);
+        let provider = provider(&stub).await;
+        user::add(&db, "Jo Cook", "jo@example.org", "$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA")
+            .await
+            .unwrap();
+        let signed_in = sign_in(&stub, &provider, &db, identity("jo", "jo@example.org"), None).await;
+        assert!(matches!(signed_in, Err(authjwt::AuthError::AccountExists)));
+    }
+
+    #[tokio::test]
+    async fn verified_email_links_passwordless_account() {
+        let (stub, db) = (stub_provider().await, test_db().await);
+        let provider = provider(&stub).await;
+        let (user_id, _) = user::add(&db, "Jo Cook", "jo@example.org", "").await.unwrap();
+        let signed_in = sign_in(&stub, &provider, &db, identity("jo", "jo@example.org"), None).await;
+        assert_eq!(signed_in.unwrap().0, user_id);
+    }
+
+    #[tokio::test]
+    async fn signed_in_account_links_identity() {
+        let (stub, db) = (stub_provider().await, test_db().await);
+        let provider = provider(&stub).await;
+        let (user_id, _) = user::add(&db, "Jo Cook", "jo@example.org", "$argon2id$hash").await.unwrap();
+        let (other_id, _) = user::add(&db, "Al Baker", "al@example.org", "$argon2id$hash").await.unwrap();
+        let linked = sign_in(&stub, &provider, &db, identity("jo", "jo@example.org"), Some(user_id)).await;
+        assert_eq!(linked.unwrap().0, user_id);
+        let signed_in = sign_in(&stub, &provider, &db, identity("jo", "jo@example.org"), None).await;
+        assert_eq!(signed_in.unwrap().0, user_id);
+        let relinked = sign_in(&stub, &provider, &db, identity("jo", "jo@example.org"), Some(other_id)).await;
+        assert!(matches!(relinked, Err(authjwt::AuthError::IdentityLinked)));
+    }
+
+    #[tokio::test]
+    async fn id_token_must_carry_nonce_audience_and_issuer_of_login() {
+        let (stub, db) = (stub_provider().await, test_db().await);
+        let provider = provider(&stub).await;
+        for (name, value) in [("nonce", "replayed"), ("aud", "another-client"), ("iss", "https://evil.example.org")] {
+            let mut claims = identity("jo", "jo@example.org");
+            claims[name] = value.into();
+            let signed_in = sign_in(&stub, &provider, &db, claims, None).await;
+            assert!(matches!(signed_in, Err(authjwt::AuthError::OidcLogin)), "{} not checked", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn login_state_is_used_once() {
+        let (stub, db) = (stub_provider().await, test_db().await);
+        let provider = provider(&stub).await;
+        sign_in(&stub, &provider, &db, identity("jo", "jo@example.org"), None).await.unwrap();
+        let state: String = sqlx::query_scalar("SELECT COUNT(*) FROM oidc_logins;")
+            .fetch_one(&db)
+            .await
+            .map(|count: i64| count.to_string())
+            .unwrap();
+        assert_eq!(state, "0");
+        let callback = Callback { code: Some("code".to_string()), state: "unknown".to_string(), error: None };
+        let jar = CookieJar::new().add(Cookie::new(STATE_COOKIE, authjwt::hash_token("unknown")));
+        assert!(matches!(provider.finish_login(&db, &jar, &callback).await, Err(authjwt::AuthError::OidcLogin)));
+    }
+
+    #[tokio::test]
+    async fn login_finishes_only_in_browser_that_started_it() {
+        let (stub, db) = (stub_provider().await, test_db().await);
+        let provider = provider(&stub).await;
+        let (user_id, _) = user::add(&db, "Jo Cook", "jo@example.org", "$argon2id$hash").await.unwrap();
+        let (url, jar) = provider.start_login(&db, CookieJar::new(), Some(user_id)).await.unwrap();
+        let url = reqwest::Url::parse(&url).unwrap();
+        let state = url.query_pairs().find(|(key, _)| key == "state").unwrap().1.into_owned();
+        assert_eq!(jar.get(STATE_COOKIE).unwrap().value(), authjwt::hash_token(&state));
+        assert!(jar.get(STATE_COOKIE).unwrap().http_only().unwrap_or(false));
+
+        let callback = Callback { code: Some("code".to_string()), state, error: None };
+        let (_, other_jar) = provider.start_login(&db, CookieJar::new(), None).await.unwrap();
+        for jar in [CookieJar::new(), other_jar] {
+            let finished = provider.finish_login(&db, &jar, &callback).await;
+            assert!(matches!(finished, Err(authjwt::AuthError::OidcLogin)));
+        }
+    }
+
+    #[tokio::test]
+    async fn account_with_authenticator_needs_trusted_provider() {
+        let (stub, appstate) = (stub_provider().await, test_appstate().await);
+        let mut provider = provider(&stub).await;
+        let (user_id, _) = user::add(&appstate.db, "Jo Cook", "jo@example.org", "").await.unwrap();
+        sign_in(&stub, &provider, &appstate.db, identity("jo", "jo@example.org"), None).await.unwrap();
+        sqlx::query("INSERT INTO user_totp (user_id, secret, confirmed_at) VALUES ($1, 'JBSWY3DPEHPK3PXP', 0);")
+            .bind(user_id)
+            .execute(&appstate.db)
+            .await
+            .unwrap();
+
+        let (callback, jar) = visit_provider(&stub, &provider, &appstate.db, identity("jo", "jo@example.org"), None)
+            .await
+            .unwrap();
+        let refused = login(&appstate, &provider, &jar, &callback, None).await;
+        assert!(matches!(refused, Err(authjwt::AuthError::OidcTotp)));
+
+        provider.trust_mfa = true;
+        let (callback, jar) = visit_provider(&stub, &provider, &appstate.db, identity("jo", "jo@example.org"), None)
+            .await
+            .unwrap();
+        assert!(login(&appstate, &provider, &jar, &callback, None).await.is_ok());
+    }
+}
@@ -1,7 +1,6 @@
 use crate::*;
 
-use std::collections::HashSet;
-use std::ops::Deref;
+use sqlx::Connection;
 use std::path::Path;
 
 use crate::RecipeError;
@@ -10,11 +9,52 @@ use serde::Deserialize;
 
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct JsonRecipe {
-    id: i64,
+    /// Assigned by the server when left out on create.
+    #[schema(example = 7)]
+    id: Option<i64>,
     title: String,
     category: String,
-    ingredient_amount: HashSet<String>,
+    ingredient_amount: Vec<Ingredient>,
     preparation: String,
+    #[schema(example = 4)]
+    servings: Option<i64>,
+    /// Id of the user who added the recipe; set by the server.
+    #[serde(default, skip_deserializing)]
+    #[schema(example = 1)]
+    author_id: Option<i64>,
+    /// Name of the user who added the recipe; set by the server.
+    #[serde(default, skip_deserializing)]
+    #[schema(example = "John Smith")]
+    author: Option<String>,
+    #[serde(default, skip_deserializing)]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_deserializing)]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    updated_at: Option<DateTime<Utc>>,
+}
+
+/// Fields to change on an existing recipe; anything left out stays as it is.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RecipePatch {
+    title: Option<String>,
+    category: Option<String>,
+    ingredient_amount: Option<Vec<Ingredient>>,
+    preparation: Option<String>,
+    /// `null` clears the number of servings.
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<i64>, example = 4)]
+    servings: Option<Option<i64>>,
+}
+
+/// Read a field that was sent, even as `null`, as `Some`, so that only a
+/// field left out is `None`.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
 }
 
 #[derive(Clone)]
@@ -23,6 +63,26 @@ pub struct Recipe {
     pub title: String,
     pub category: String,
     pub preparation: String,
+    pub servings: Option<i64>,
+    pub author_id: Option<i64>,
+    pub author: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    /// Starts at 1 and goes up with every update.
+    pub version: i64,
+}
+
+/// Strong entity tag of version `version` of recipe `recipe_id` by `author`.
+/// The author's name is sent with the recipe but kept with the user, where it
+/// can change without the recipe's version going up, so it is folded in too.
+pub fn etag(recipe_id: i64, version: i64, author: Option<&str>) -> headers::ETag {
+    use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+    use sha2::{Digest, Sha256};
+
+    let author = Sha256::digest(author.unwrap_or_default().as_bytes());
+    format!("\"{}-{}-{}\"", recipe_id, version, URL_SAFE_NO_PAD.encode(&author[..6]))
+        .parse()
+        .expect("recipe etag is well formed")
 }
 
 pub fn read_recipes<P: AsRef<Path>>(recipes_path: P) -> Result<Vec<JsonRecipe>, RecipeError> {
@@ -32,26 +92,72 @@ pub fn read_recipes<P: AsRef<Path>>(recipes_path: P) -> Result<Vec<JsonRecipe>,
 }
 
 impl JsonRecipe {
-    pub fn new(recipe: Recipe, ingredients: Vec<String>) -> Self {
-        let ingredients = ingredients.into_iter().collect();
+    pub fn new(recipe: Recipe, ingredients: Vec<Ingredient>) -> Self {
         Self {
-            id: recipe.id,
+            id: Some(recipe.id),
             title: recipe.title,
             category: recipe.category,
             ingredient_amount: ingredients,
             preparation: recipe.preparation,
+            servings: recipe.servings,
+            author_id: recipe.author_id,
+            author: recipe.author,
+            created_at: recipe.created_at,
+            updated_at: recipe.updated_at,
         }
     }
 
-    pub fn to_recipe(&self) -> (Recipe, impl Iterator<Item=&str>) {
-        let recipe = Recipe {
-            id: self.id.clone(),
-            title: self.title.clone(),
-            category: self.category.clone(),
-            preparation: self.preparation.clone(),
+    /// A recipe not yet stored, for the database to give an id.
+    pub fn draft(
+        title: String,
+        category: String,
+        ingredient_amount: Vec<Ingredient>,
+        preparation: String,
+        servings: Option<i64>,
+    ) -> Self {
+        Self {
+            id: None,
+            title,
+            category,
+            ingredient_amount,
+            preparation,
+            servings,
+            author_id: None,
+            author: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    pub fn id(&self) -> Option<i64> {
+        self.id
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// Rewrite every ingredient quantity for `servings` people. Returns
+    /// `false` if the recipe does not say how many it serves.
+    pub fn scale(&mut self, servings: i64) -> bool {
+        let Some(current) = self.servings.filter(|&s| s > 0) else {
+            return false;
         };
-        let ingredient_amount = self.ingredient_amount.iter().map(String::deref);
-        (recipe, ingredient_amount)
+        let factor = servings as f64 / current as f64;
+        for ingredient in &mut self.ingredient_amount {
+            ingredient.scale(factor);
+        }
+        self.servings = Some(servings);
+        true
+    }
+
+    /// Convert ingredient quantities and the temperatures mentioned in the
+    /// preparation to `system`.
+    pub fn convert_units(&mut self, system: UnitSystem) {
+        for ingredient in &mut self.ingredient_amount {
+            ingredient.convert_units(system);
+        }
+        self.preparation = units::convert_temperatures(&self.preparation, system);
     }
 }
 
@@ -61,44 +167,124 @@ impl axum::response::IntoResponse for &JsonRecipe {
     }
 }
 
-pub async fn get(db: &SqlitePool, recipe_id: &str) -> Result<(Recipe, Vec<String>), sqlx::Error> {
-    let recipe = sqlx::query_as!(Recipe, "SELECT * FROM recipes WHERE id = $1;", recipe_id)
-        .fetch_one(db)
+pub async fn get(db: &SqlitePool, recipe_id: &str) -> Result<(Recipe, Vec<Ingredient>), sqlx::Error> {
+    fetch(&mut *db.acquire().await?, recipe_id).await
+}
+
+async fn fetch(conn: &mut sqlx::SqliteConnection, recipe_id: &str) -> Result<(Recipe, Vec<Ingredient>), sqlx::Error> {
+    let recipe = sqlx::query_as!(
+        Recipe,
+        r#"SELECT r.id, r.title, r.category, r.preparation, r.servings, r.author_id,
+          u.full_name AS "author?",
+          r.created_at AS "created_at: DateTime<Utc>",
+          r.updated_at AS "updated_at: DateTime<Utc>",
+          r.version
+        FROM recipes r LEFT JOIN users u ON u.id = r.author_id
+        WHERE r.id = $1;"#,
+        recipe_id,
+    )
+        .fetch_one(&mut *conn)
         .await?;
 
-    let ingredient_amount: Vec<String> = sqlx::query_scalar!("SELECT ingredient_amount FROM ingredients WHERE recipe_id = $1;", recipe_id)
-        .fetch_all(db)
+    let ingredient_amount = sqlx::query_as!(
+        Ingredient,
+        r#"SELECT ri.ingredient_amount AS text, ri.quantity, ri.quantity_max, ri.unit,
+          coalesce(i.name, '') AS "name!: String"
+        FROM recipe_ingredients ri LEFT JOIN ingredients i ON i.id = ri.ingredient_id
+        WHERE ri.recipe_id = $1
+        ORDER BY ri.id;"#,
+        recipe_id,
+    )
+        .fetch_all(&mut *conn)
         .await?;
 
     Ok((recipe, ingredient_amount))
 }
 
-pub async fn get_by_ingredients<'a, I>(db: &SqlitePool, ingredients: I) -> Result<Option<String>, sqlx::Error>
+/// Recipes `recipe_ids` with their ingredients, in the same order, leaving
+/// out ids with no recipe. Takes two queries however many ids there are.
+pub async fn get_many(db: &SqlitePool, recipe_ids: &[i64]) -> Result<Vec<(Recipe, Vec<Ingredient>)>, sqlx::Error> {
+    use std::collections::HashMap;
+
+    let ids = serde_json::to_string(recipe_ids).expect("recipe ids serialize");
+    let recipes = sqlx::query_as!(
+        Recipe,
+        r#"SELECT r.id, r.title, r.category, r.preparation, r.servings, r.author_id,
+          u.full_name AS "author?",
+          r.created_at AS "created_at: DateTime<Utc>",
+          r.updated_at AS "updated_at: DateTime<Utc>",
+          r.version
+        FROM recipes r LEFT JOIN users u ON u.id = r.author_id
+        WHERE r.id IN (SELECT value FROM json_each($1));"#,
+        ids,
+    )
+        .fetch_all(db)
+        .await?;
+
+    let rows = sqlx::query!(
+        r#"SELECT ri.recipe_id, ri.ingredient_amount AS text, ri.quantity, ri.quantity_max, ri.unit,
+          coalesce(i.name, '') AS "name!: String"
+        FROM recipe_ingredients ri LEFT JOIN ingredients i ON i.id = ri.ingredient_id
+        WHERE ri.recipe_id IN (SELECT value FROM json_each($1))
+        ORDER BY ri.id;"#,
+        ids,
+    )
+        .fetch_all(db)
+        .await?;
+    let mut ingredients: HashMap<i64, Vec<Ingredient>> = HashMap::new();
+    for row in rows {
+        ingredients.entry(row.recipe_id).or_default().push(Ingredient {
+            text: row.text,
+            quantity: row.quantity,
+            quantity_max: row.quantity_max,
+            unit: row.unit,
+            name: row.name,
+        });
+    }
+
+    let mut recipes: HashMap<i64, Recipe> = recipes.into_iter().map(|recipe| (recipe.id, recipe)).collect();
+    Ok(recipe_ids
+        .iter()
+        .filter_map(|id| Some((recipes.remove(id)?, ingredients.remove(id).unwrap_or_default())))
+        .collect())
+}
+
+/// The stored recipe as JSON, for audit diffs.
+pub async fn snapshot(conn: &mut sqlx::SqliteConnection, recipe_id: i64) -> Option<serde_json::Value> {
+    let (recipe, ingredients) = fetch(conn, &recipe_id.to_string()).await.ok()?;
+    serde_json::to_value(JsonRecipe::new(recipe, ingredients)).ok()
+}
+
+/// Ids of up to `limit` recipes containing any of `ingredients`, matched
+/// either on the text as written or on the canonical name, most matches
+/// first.
+pub async fn get_by_ingredients<'a, I>(db: &SqlitePool, ingredients: I, limit: i64) -> Result<Vec<i64>, sqlx::Error>
     where I: Iterator<Item=&'a str>
 {
-    let mut rtx = db.begin().await?;
-    sqlx::query("DROP TABLE IF EXISTS qingredients;").execute(&mut *rtx).await?;
-    sqlx::query("CREATE TEMPORARY TABLE qingredients (ingredient_amount VARCHR(200));")
-        .execute(&mut *rtx)
-        .await?;
-    for ingredient_amount in ingredients {
-        sqlx::query("INSERT INTO qingredients VALUES ($1);")
-            .bind(ingredient_amount)
-            .execute(&mut *rtx)
-            .await?;
+    let mut wanted: Vec<String> = Vec::new();
+    for ingredient in ingredients {
+        for value in [ingredient.trim().to_string(), Ingredient::parse(ingredient).name] {
+            if !value.is_empty() && !wanted.contains(&value) {
+                wanted.push(value);
+            }
+        }
     }
-    let recipe_ids = sqlx::query("SELECT DISTINCT recipe_id FROM ingredients JOIN qingredients ON ingredients.ingredient_amount = qingredients.ingredient_amount ORDER BY RANDOM() LIMIT 1;")
-        .fetch_all(&mut *rtx)
-        .await?;
-    let nrecipe_ids = recipe_ids.len();
-    let result = if nrecipe_ids == 1 {
-        Some(recipe_ids[0].get(0))
-    } else {
-        None
-    };
-    rtx.commit().await?;
+    let wanted = serde_json::to_string(&wanted).expect("ingredient list serializes");
 
-    Ok(result)
+    sqlx::query_scalar(
+        r#"SELECT ri.recipe_id AS id
+        FROM recipe_ingredients ri
+        LEFT JOIN ingredients i ON i.id = ri.ingredient_id
+        JOIN json_each($1) AS wanted
+          ON ri.ingredient_amount = wanted.value OR i.name = wanted.value
+        GROUP BY ri.recipe_id
+        ORDER BY COUNT(DISTINCT ri.id) DESC, id
+        LIMIT $2;"#,
+    )
+    .bind(wanted)
+    .bind(limit)
+    .fetch_all(db)
+    .await
 }
 
 pub async fn get_random(db: &SqlitePool) -> Result<i64, sqlx::Error> {
@@ -107,32 +293,233 @@ pub async fn get_random(db: &SqlitePool) -> Result<i64, sqlx::Error> {
         .await
 }
 
-pub async fn add(db: &SqlitePool, recipe: JsonRecipe) -> Result<(), sqlx::Error> {
-    let mut jtx = db.begin().await?;
+/// Store a new recipe by `author_id` and return its id, which the database
+/// picks unless the recipe carries one.
+pub async fn add(conn: &mut sqlx::SqliteConnection, recipe: JsonRecipe, author_id: Option<i64>) -> Result<i64, sqlx::Error> {
+    let mut jtx = conn.begin().await?;
 
-    sqlx::query!(
+    let now = Utc::now();
+    let inserted = sqlx::query!(
         r#"INSERT INTO recipes
-        (id, title, category, preparation)
-        VALUES ($1, $2, $3, $4);"#,
+        (id, title, category, preparation, servings, author_id, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $7);"#,
         recipe.id,
         recipe.title,
         recipe.category,
         recipe.preparation,
+        recipe.servings,
+        author_id,
+        now,
     )
     .execute(&mut *jtx)
     .await?;
+    let recipe_id = inserted.last_insert_rowid();
+
+    insert_ingredients(&mut jtx, recipe_id, &recipe.ingredient_amount).await?;
 
-    for ingredient in recipe.ingredient_amount {
+    jtx.commit().await?;
+    Ok(recipe_id)
+}
+
+async fn insert_ingredients(
+    conn: &mut sqlx::SqliteConnection,
+    recipe_id: i64,
+    ingredients: &[Ingredient],
+) -> Result<(), sqlx::Error> {
+    for ingredient in ingredients {
+        let ingredient_id = ingredient_id(&mut *conn, &ingredient.name).await?;
         sqlx::query!(
-            r#"INSERT INTO ingredients(recipe_id, ingredient_amount) VALUES ($1, $2);"#,
-            recipe.id,
-            ingredient,
+            r#"INSERT INTO recipe_ingredients
+            (recipe_id, ingredient_id, ingredient_amount, quantity, quantity_max, unit)
+            VALUES ($1, $2, $3, $4, $5, $6);"#,
+            recipe_id,
+            ingredient_id,
+            ingredient.text,
+            ingredient.quantity,
+            ingredient.quantity_max,
+            ingredient.unit,
         )
-            .execute(&mut *jtx)
+            .execute(&mut *conn)
             .await?;
     }
+    Ok(())
+}
 
-    jtx.commit().await?;
+/// Dictionary id for an ingredient name, adding the name if it is new.
+async fn ingredient_id(conn: &mut sqlx::SqliteConnection, name: &str) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"INSERT INTO ingredients (name) VALUES ($1)
+        ON CONFLICT (name) DO UPDATE SET name = excluded.name
+        RETURNING id;"#,
+        name,
+    )
+        .fetch_one(&mut *conn)
+        .await
+}
+
+/// Replace version `version` of the recipe stored under `recipe_id` and all
+/// of its ingredients. Returns `false` if there is no such recipe at that
+/// version.
+pub async fn update(conn: &mut sqlx::SqliteConnection, recipe_id: i64, version: i64, recipe: JsonRecipe) -> Result<bool, sqlx::Error> {
+    let mut utx = conn.begin().await?;
+
+    let now = Utc::now();
+    let updated = sqlx::query!(
+        r#"UPDATE recipes
+        SET title = $1, category = $2, preparation = $3, servings = $4, updated_at = $5,
+          version = version + 1
+        WHERE id = $6 AND version = $7;"#,
+        recipe.title,
+        recipe.category,
+        recipe.preparation,
+        recipe.servings,
+        now,
+        recipe_id,
+        version,
+    )
+    .execute(&mut *utx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    sqlx::query!("DELETE FROM recipe_ingredients WHERE recipe_id = $1;", recipe_id)
+        .execute(&mut *utx)
+        .await?;
+    insert_ingredients(&mut utx, recipe_id, &recipe.ingredient_amount).await?;
+
+    utx.commit().await?;
+    Ok(true)
+}
+
+/// Change only the fields present in `patch` on version `version` of a
+/// recipe. Returns `false` if there is no such recipe at that version.
+pub async fn patch(conn: &mut sqlx::SqliteConnection, recipe_id: i64, version: i64, patch: RecipePatch) -> Result<bool, sqlx::Error> {
+    let mut ptx = conn.begin().await?;
+
+    let recipe = sqlx::query!(
+        "SELECT title, category, preparation, servings FROM recipes WHERE id = $1 AND version = $2;",
+        recipe_id,
+        version,
+    )
+        .fetch_optional(&mut *ptx)
+        .await?;
+    let Some(recipe) = recipe else {
+        return Ok(false);
+    };
+
+    let title = patch.title.unwrap_or(recipe.title);
+    let category = patch.category.unwrap_or(recipe.category);
+    let preparation = patch.preparation.unwrap_or(recipe.preparation);
+    let servings = patch.servings.unwrap_or(recipe.servings);
+    let now = Utc::now();
+    let updated = sqlx::query!(
+        r#"UPDATE recipes
+        SET title = $1, category = $2, preparation = $3, servings = $4, updated_at = $5,
+          version = version + 1
+        WHERE id = $6 AND version = $7;"#,
+        title,
+        category,
+        preparation,
+        servings,
+        now,
+        recipe_id,
+        version,
+    )
+    .execute(&mut *ptx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    if let Some(ingredients) = patch.ingredient_amount {
+        sqlx::query!("DELETE FROM recipe_ingredients WHERE recipe_id = $1;", recipe_id)
+            .execute(&mut *ptx)
+            .await?;
+        insert_ingredients(&mut ptx, recipe_id, &ingredients).await?;
+    }
+
+    ptx.commit().await?;
+    Ok(true)
+}
+
+/// Current version of recipe `recipe_id` and its entity tag, if there is
+/// such a recipe.
+pub async fn version(db: &SqlitePool, recipe_id: i64) -> Result<Option<(i64, headers::ETag)>, sqlx::Error> {
+    let current = sqlx::query!(
+        r#"SELECT r.version, u.full_name AS "author?"
+        FROM recipes r LEFT JOIN users u ON u.id = r.author_id
+        WHERE r.id = $1;"#,
+        recipe_id,
+    )
+        .fetch_optional(db)
+        .await?;
+    Ok(current.map(|current| (current.version, etag(recipe_id, current.version, current.author.as_deref()))))
+}
+
+/// Author of recipe `recipe_id`: `None` if there is no such recipe,
+/// `Some(None)` if it has no recorded author.
+pub async fn author_id(db: &SqlitePool, recipe_id: i64) -> Result<Option<Option<i64>>, sqlx::Error> {
+    sqlx::query_scalar!("SELECT author_id FROM recipes WHERE id = $1;", recipe_id)
+        .fetch_optional(db)
+        .await
+}
+
+/// Remove a recipe; its ingredient rows go with it through the cascading
+/// foreign key. Returns `false` if there is no such recipe.
+pub async fn delete(conn: &mut sqlx::SqliteConnection, recipe_id: i64) -> Result<bool, sqlx::Error> {
+    let mut dtx = conn.begin().await?;
+
+    let deleted = sqlx::query!("DELETE FROM recipes WHERE id = $1;", recipe_id)
+        .execute(&mut *dtx)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    dtx.commit().await?;
+    Ok(true)
+}
+
+/// Fill in the structured columns for ingredient rows stored before
+/// ingredients were parsed.
+pub async fn parse_legacy_ingredients(db: &SqlitePool) -> Result<(), sqlx::Error> {
+    let mut itx = db.begin().await?;
+    let legacy = sqlx::query!("SELECT id, ingredient_amount FROM recipe_ingredients WHERE ingredient_id IS NULL;")
+        .fetch_all(&mut *itx)
+        .await?;
+    for row in legacy {
+        let ingredient = Ingredient::parse(&row.ingredient_amount);
+        let ingredient_id = ingredient_id(&mut itx, &ingredient.name).await?;
+        sqlx::query!(
+            r#"UPDATE recipe_ingredients
+            SET quantity = $1, quantity_max = $2, unit = $3, ingredient_id = $4
+            WHERE id = $5;"#,
+            ingredient.quantity,
+            ingredient.quantity_max,
+            ingredient.unit,
+            ingredient_id,
+            row.id,
+        )
+            .execute(&mut *itx)
+            .await?;
+    }
+    itx.commit().await?;
     Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patch_tells_null_servings_from_missing() {
+        let patch: RecipePatch = serde_json::from_str(r#"{"title": "Soup"}"#).unwrap();
+        assert_eq!(patch.servings, None);
+        let patch: RecipePatch = serde_json::from_str(r#"{"servings": null}"#).unwrap();
+        assert_eq!(patch.servings, Some(None));
+        let patch: RecipePatch = serde_json::from_str(r#"{"servings": 6}"#).unwrap();
+        assert_eq!(patch.servings, Some(Some(6)));
+    }
+}
@@ -0,0 +1,550 @@
+use crate::*;
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+
+/// A recipe matching a full-text query. The `*_snippet` fields are HTML:
+/// recipe text escaped, with the matched terms wrapped in `<mark>` tags.
+#[derive(Debug, Serialize, sqlx::FromRow, ToSchema)]
+pub struct SearchHit {
+    #[schema(example = 1)]
+    id: i64,
+    #[schema(example = "Coffee Cream")]
+    title: String,
+    #[schema(example = "dessert")]
+    category: String,
+    /// Lower is a better match.
+    #[schema(example = -4.2)]
+    rank: f64,
+    #[schema(example = "<mark>Coffee</mark> Cream")]
+    title_snippet: String,
+    #[schema(example = "dessert")]
+    category_snippet: String,
+    #[schema(example = "…first, make the <mark>coffee</mark> using a moka pot…")]
+    preparation_snippet: String,
+    #[schema(example = "70g <mark>coffee</mark> (cold), 40g sugar…")]
+    ingredients_snippet: String,
+}
+
+// Stand-ins for `<mark>` and `</mark>` in what FTS5 returns, so the recipe
+// text around them can be escaped before the tags go in.
+const MARK_START: char = '\u{2}';
+const MARK_END: char = '\u{3}';
+
+/// HTML for a snippet from [`full_text`]'s query: the text escaped, with
+/// the match markers turned into `<mark>` tags.
+fn mark_up(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            MARK_START => html.push_str("<mark>"),
+            MARK_END => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+/// Turn free text into an FTS5 query that requires every word, allowing
+/// prefixes. Quoting each word keeps FTS5 syntax characters in user input
+/// from being interpreted.
+fn fts_query(text: &str) -> Option<String> {
+    let terms: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"*", word))
+        .collect();
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+/// Best matches for `text` across title, category, preparation and
+/// ingredients, with title matches weighted highest. Returns `None` if
+/// `text` has nothing to search for.
+pub async fn full_text(db: &SqlitePool, text: &str, limit: i64) -> Result<Option<Vec<SearchHit>>, sqlx::Error> {
+    let Some(query) = fts_query(text) else {
+        return Ok(None);
+    };
+    let mut hits = sqlx::query_as::<_, SearchHit>(
+        r#"SELECT recipes.id, recipes.title, recipes.category,
+          bm25(recipes_fts, 10.0, 5.0, 1.0, 3.0) AS rank,
+          highlight(recipes_fts, 0, char(2), char(3)) AS title_snippet,
+          highlight(recipes_fts, 1, char(2), char(3)) AS category_snippet,
+          snippet(recipes_fts, 2, char(2), char(3), '…', 24) AS preparation_snippet,
+          snippet(recipes_fts, 3, char(2), char(3), '…', 12) AS ingredients_snippet
+        FROM recipes_fts JOIN recipes ON recipes.id = recipes_fts.rowid
+        WHERE recipes_fts MATCH $1
+        ORDER BY rank
+        LIMIT $2;"#,
+    )
+    .bind(query)
+    .bind(limit)
+    .fetch_all(db)
+    .await?;
+    for hit in &mut hits {
+        for snippet in [
+            &mut hit.title_snippet,
+            &mut hit.category_snippet,
+            &mut hit.preparation_snippet,
+            &mut hit.ingredients_snippet,
+        ] {
+            *snippet = mark_up(snippet);
+        }
+    }
+    Ok(Some(hits))
+}
+
+/// What a cook asks for when looking for recipes to make from what they
+/// have on hand.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PantryQuery {
+    /// Ingredients at hand, as names or free text like "500ml milk".
+    #[schema(example = json!(["eggs", "milk", "sugar"]))]
+    ingredients: Vec<String>,
+    /// Leave out recipes needing more than this many other ingredients;
+    /// 0 only returns recipes that can be made entirely from the pantry.
+    #[schema(example = 2)]
+    max_missing: Option<usize>,
+    #[schema(example = 20)]
+    limit: Option<usize>,
+}
+
+/// A recipe ranked by how much of it the pantry covers.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PantryMatch {
+    #[schema(example = 2)]
+    id: i64,
+    #[schema(example = "Pastry Cream")]
+    title: String,
+    /// Fraction of the recipe's ingredients found in the pantry.
+    #[schema(example = 0.6)]
+    coverage: f64,
+    #[schema(example = json!(["4 egg yolks", "500ml milk", "85g sugar"]))]
+    matched: Vec<String>,
+    #[schema(example = json!(["40g starch", "vanilla"]))]
+    missing: Vec<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct PantryRow {
+    recipe_id: i64,
+    title: String,
+    text: String,
+    have: bool,
+}
+
+/// Recipes sharing at least one ingredient with the pantry, best covered
+/// first. A pantry item matches a recipe ingredient whose canonical name is
+/// the same or contains it as a whole word, so "flour" finds
+/// "all-purpose flour".
+pub async fn pantry(db: &SqlitePool, query: &PantryQuery) -> Result<Vec<PantryMatch>, sqlx::Error> {
+    let names: Vec<String> = query
+        .ingredients
+        .iter()
+        .map(|i| Ingredient::parse(i).name)
+        .filter(|name| !name.is_empty())
+        .collect();
+    let names = serde_json::to_string(&names).expect("pantry names serialize");
+
+    let rows = sqlx::query_as::<_, PantryRow>(
+        r#"WITH pantry(name) AS (SELECT DISTINCT value FROM json_each($1)),
+        stocked AS (
+          SELECT DISTINCT i.id FROM ingredients i JOIN pantry p
+          ON instr(' ' || i.name || ' ', ' ' || p.name || ' ') > 0
+        ),
+        candidates AS (
+          SELECT DISTINCT ri.recipe_id FROM recipe_ingredients ri
+          JOIN stocked s ON s.id = ri.ingredient_id
+        )
+        SELECT r.id AS recipe_id, r.title, ri.ingredient_amount AS text,
+          ri.ingredient_id IN (SELECT id FROM stocked) AS have
+        FROM candidates c
+        JOIN recipes r ON r.id = c.recipe_id
+        JOIN recipe_ingredients ri ON ri.recipe_id = c.recipe_id
+        ORDER BY r.id, ri.id;"#,
+    )
+    .bind(names)
+    .fetch_all(db)
+    .await?;
+
+    let mut matches: Vec<PantryMatch> = Vec::new();
+    for row in rows {
+        let m = match matches.last_mut() {
+            Some(m) if m.id == row.recipe_id => m,
+            _ => {
+                matches.push(PantryMatch {
+                    id: row.recipe_id,
+                    title: row.title,
+                    coverage: 0.0,
+                    matched: Vec::new(),
+                    missing: Vec::new(),
+                });
+                matches.last_mut().unwrap()
+            }
+        };
+        if row.have {
+            m.matched.push(row.text);
+        } else {
+            m.missing.push(row.text);
+        }
+    }
+    for m in &mut matches {
+        m.coverage = m.matched.len() as f64 / (m.matched.len() + m.missing.len()) as f64;
+    }
+
+    if let Some(max_missing) = query.max_missing {
+        matches.retain(|m| m.missing.len() <= max_missing);
+    }
+    matches.sort_by(|a, b| {
+        b.coverage
+            .total_cmp(&a.coverage)
+            .then(a.missing.len().cmp(&b.missing.len()))
+            .then(a.id.cmp(&b.id))
+    });
+    matches.truncate(query.limit.unwrap_or(20));
+    Ok(matches)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RecipeSort {
+    #[default]
+    Id,
+    Title,
+    Category,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Filters and ordering for walking through all recipes a page at a time.
+#[derive(Debug, Default, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListQuery {
+    /// Only recipes in this category, ignoring case.
+    #[param(example = "dessert")]
+    category: Option<String>,
+    /// Only recipes whose title contains this text, ignoring case.
+    #[param(example = "cream")]
+    title: Option<String>,
+    /// Comma-separated ingredients every returned recipe must use.
+    #[param(example = "eggs,milk")]
+    include: Option<String>,
+    /// Comma-separated ingredients no returned recipe may use.
+    #[param(example = "almonds")]
+    exclude: Option<String>,
+    sort: Option<RecipeSort>,
+    order: Option<SortOrder>,
+    /// `next_cursor` from the previous page.
+    cursor: Option<String>,
+    #[param(minimum = 1, maximum = 100, example = 20)]
+    limit: Option<i64>,
+}
+
+/// One page of recipes. Pass `next_cursor` back as `cursor` with the same
+/// filters and sort to get the following page.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RecipePage {
+    recipes: Vec<JsonRecipe>,
+    #[schema(example = "eyJzb3J0IjoiaWQiLCJvcmRlciI6ImFzYyIsImtleSI6IiIsImlkIjoyMH0")]
+    next_cursor: Option<String>,
+    /// Number of recipes matching the filters across all pages.
+    #[schema(example = 42)]
+    total: i64,
+}
+
+// Where the previous page ended. It carries the sort it was made for so a
+// cursor cannot be replayed against a different ordering.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: RecipeSort,
+    order: SortOrder,
+    key: String,
+    id: i64,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("cursor serializes");
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let json = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct ListRow {
+    id: i64,
+    key: String,
+}
+
+// Conditions shared by the page and total queries. $3 and $4 are JSON arrays
+// of canonical ingredient names, matched as whole words like the pantry.
+const LIST_FILTERS: &str = r#"
+    ($1 IS NULL OR lower(r.category) = lower($1))
+    AND ($2 IS NULL OR instr(lower(r.title), lower($2)) > 0)
+    AND NOT EXISTS (
+      SELECT 1 FROM json_each($3) w
+      WHERE NOT EXISTS (
+        SELECT 1 FROM recipe_ingredients ri JOIN ingredients i ON i.id = ri.ingredient_id
+        WHERE ri.recipe_id = r.id AND instr(' ' || i.name || ' ', ' ' || w.value || ' ') > 0
+      )
+    )
+    AND NOT EXISTS (
+      SELECT 1 FROM recipe_ingredients ri JOIN ingredients i ON i.id = ri.ingredient_id
+      JOIN json_each($4) w ON instr(' ' || i.name || ' ', ' ' || w.value || ' ') > 0
+      WHERE ri.recipe_id = r.id
+    )"#;
+
+/// Canonical names of `ingredients` as a JSON array, or `None` if there are
+/// none.
+fn ingredient_names<'a>(ingredients: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    let names: Vec<String> = ingredients
+        .into_iter()
+        .map(|i| Ingredient::parse(i).name)
+        .filter(|name| !name.is_empty())
+        .collect();
+    if names.is_empty() {
+        return None;
+    }
+    Some(serde_json::to_string(&names).expect("ingredient names serialize"))
+}
+
+/// A page of recipes matching `query`. Returns `None` if the cursor is not
+/// one this listing handed out for the same sort.
+pub async fn list(db: &SqlitePool, query: &ListQuery) -> Result<Option<RecipePage>, sqlx::Error> {
+    let sort = query.sort.unwrap_or_default();
+    let order = query.order.unwrap_or_default();
+    let cursor = match query.cursor.as_deref().map(Cursor::decode) {
+        None => None,
+        Some(Some(cursor)) if cursor.sort == sort && cursor.order == order => Some(cursor),
+        Some(_) => return Ok(None),
+    };
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+    let include = ingredient_names(query.include.as_deref().unwrap_or_default().split(','));
+    let exclude = ingredient_names(query.exclude.as_deref().unwrap_or_default().split(','));
+
+    let total: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM recipes r WHERE {};", LIST_FILTERS))
+        .bind(&query.category)
+        .bind(&query.title)
+        .bind(&include)
+        .bind(&exclude)
+        .fetch_one(db)
+        .await?;
+
+    // Sort keys and comparisons come from the enums above, never from input.
+    let key = match sort {
+        RecipeSort::Id => "''",
+        RecipeSort::Title => "r.title COLLATE NOCASE",
+        RecipeSort::Category => "r.category COLLATE NOCASE",
+    };
+    let (after, direction) = match order {
+        SortOrder::Asc => (">", "ASC"),
+        SortOrder::Desc => ("<", "DESC"),
+    };
+    let page_sql = format!(
+        r#"SELECT r.id, {key} AS key FROM recipes r
+        WHERE {LIST_FILTERS}
+          AND ($5 IS NULL OR ({key}, r.id) {after} ($5, $6))
+        ORDER BY {key} {direction}, r.id {direction}
+        LIMIT $7;"#,
+    );
+    let mut rows = sqlx::query_as::<_, ListRow>(&page_sql)
+        .bind(&query.category)
+        .bind(&query.title)
+        .bind(&include)
+        .bind(&exclude)
+        .bind(cursor.as_ref().map(|c| &c.key))
+        .bind(cursor.as_ref().map(|c| c.id))
+        .bind(limit + 1)
+        .fetch_all(db)
+        .await?;
+
+    let next_cursor = if rows.len() as i64 > limit {
+        rows.truncate(limit as usize);
+        rows.last().map(|last| {
+            Cursor { sort, order, key: last.key.clone(), id: last.id }.encode()
+        })
+    } else {
+        None
+    };
+
+    let mut recipes = Vec::with_capacity(rows.len());
+    for row in rows {
+        let (recipe, ingredients) = recipe::get(db, &row.id.to_string()).await?;
+        recipes.push(JsonRecipe::new(recipe, ingredients));
+    }
+    Ok(Some(RecipePage { recipes, next_cursor, total }))
+}
+
+/// A structured recipe search, as posted to `/recipes/search`. Every part
+/// is optional, but at least one of `ingredients`, `category` and `text`
+/// must be given.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct RecipeQuery {
+    /// Recipes using any of these, those using the most first.
+    #[schema(example = json!(["eggs", "milk"]))]
+    ingredients: Vec<String>,
+    /// Leave out recipes using any of these.
+    #[schema(example = json!(["almonds"]))]
+    exclude: Vec<String>,
+    /// Only recipes in this category, ignoring case.
+    #[schema(example = "dessert")]
+    category: Option<String>,
+    /// Words that must all appear in the title, category, preparation or
+    /// ingredients.
+    #[schema(example = "cream")]
+    text: Option<String>,
+    #[schema(minimum = 1, maximum = 100, example = 20)]
+    limit: Option<i64>,
+}
+
+/// [`RecipeQuery`] as a query string, with lists comma-separated.
+#[derive(Debug, Default, Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RecipeQueryParams {
+    /// Comma-separated; recipes using any of these, those using the most
+    /// first.
+    #[param(example = "eggs,milk")]
+    ingredients: Option<String>,
+    /// Comma-separated ingredients no returned recipe may use.
+    #[param(example = "almonds")]
+    exclude: Option<String>,
+    /// Only recipes in this category, ignoring case.
+    #[param(example = "dessert")]
+    category: Option<String>,
+    /// Words that must all appear in the title, category, preparation or
+    /// ingredients.
+    #[param(example = "cream")]
+    text: Option<String>,
+    #[param(minimum = 1, maximum = 100, example = 20)]
+    limit: Option<i64>,
+}
+
+impl From<RecipeQueryParams> for RecipeQuery {
+    fn from(params: RecipeQueryParams) -> Self {
+        let split = |list: Option<String>| -> Vec<String> {
+            list.unwrap_or_default().split(',').map(str::to_string).collect()
+        };
+        RecipeQuery {
+            ingredients: split(params.ingredients),
+            exclude: split(params.exclude),
+            category: params.category,
+            text: params.text,
+            limit: params.limit,
+        }
+    }
+}
+
+/// Recipes matching `query`: those sharing the most of its ingredients
+/// first, then the best text matches. Returns `None` if `query` has nothing
+/// to search for.
+pub async fn find(db: &SqlitePool, query: &RecipeQuery) -> Result<Option<Vec<JsonRecipe>>, sqlx::Error> {
+    let include = ingredient_names(query.ingredients.iter().map(String::as_str));
+    let exclude = ingredient_names(query.exclude.iter().map(String::as_str));
+    let category = query.category.as_deref().map(str::trim).filter(|c| !c.is_empty());
+    let text = query.text.as_deref().and_then(fts_query);
+    if include.is_none() && category.is_none() && text.is_none() {
+        return Ok(None);
+    }
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+
+    // The text match is joined in only when there is text, since FTS5
+    // refuses an empty MATCH.
+    let (text_join, rank) = match text {
+        Some(_) => (
+            r#"JOIN (
+              SELECT rowid, bm25(recipes_fts, 10.0, 5.0, 1.0, 3.0) AS rank
+              FROM recipes_fts WHERE recipes_fts MATCH $5
+            ) t ON t.rowid = r.id"#,
+            "t.rank",
+        ),
+        None => ("", "NULL"),
+    };
+    let sql = format!(
+        r#"WITH wanted(name) AS (SELECT DISTINCT value FROM json_each($1)),
+        shared AS (
+          SELECT ri.recipe_id, COUNT(DISTINCT w.name) AS count
+          FROM recipe_ingredients ri JOIN ingredients i ON i.id = ri.ingredient_id
+          JOIN wanted w ON instr(' ' || i.name || ' ', ' ' || w.name || ' ') > 0
+          GROUP BY ri.recipe_id
+        )
+        SELECT r.id FROM recipes r
+        LEFT JOIN shared s ON s.recipe_id = r.id
+        {text_join}
+        WHERE ($1 IS NULL OR s.count IS NOT NULL)
+          AND NOT EXISTS (
+            SELECT 1 FROM recipe_ingredients ri JOIN ingredients i ON i.id = ri.ingredient_id
+            JOIN json_each($2) w ON instr(' ' || i.name || ' ', ' ' || w.value || ' ') > 0
+            WHERE ri.recipe_id = r.id
+          )
+          AND ($3 IS NULL OR lower(r.category) = lower($3))
+        ORDER BY COALESCE(s.count, 0) DESC, {rank}, r.id
+        LIMIT $4;"#,
+    );
+    let mut ids = sqlx::query_scalar::<_, i64>(&sql)
+        .bind(include)
+        .bind(exclude)
+        .bind(category)
+        .bind(limit);
+    if let Some(text) = text {
+        ids = ids.bind(text);
+    }
+    let ids = ids.fetch_all(db).await?;
+
+    let mut recipes = Vec::with_capacity(ids.len());
+    for id in ids {
+        let (recipe, ingredients) = recipe::get(db, &id.to_string()).await?;
+        recipes.push(JsonRecipe::new(recipe, ingredients));
+    }
+    Ok(Some(recipes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_survives_round_trip() {
+        let key = "Crème brûlée".to_string();
+        let cursor = Cursor { sort: RecipeSort::Title, order: SortOrder::Desc, key, id: 42 };
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded.sort, RecipeSort::Title);
+        assert_eq!(decoded.order, SortOrder::Desc);
+        assert_eq!(decoded.key, "Crème brûlée");
+        assert_eq!(decoded.id, 42);
+        assert!(!cursor.encode().contains(['+', '/', '=']));
+    }
+
+    #[test]
+    fn cursor_decode_refuses_garbage() {
+        assert!(Cursor::decode("not a cursor!").is_none());
+        assert!(Cursor::decode(&URL_SAFE_NO_PAD.encode(b"{\"sort\":\"id\"}")).is_none());
+        assert!(Cursor::decode("").is_none());
+    }
+
+    #[test]
+    fn mark_up_escapes_text_around_marks() {
+        assert_eq!(mark_up("\u{2}Coffee\u{3} Cream"), "<mark>Coffee</mark> Cream");
+        assert_eq!(
+            mark_up("<img src=x onerror=\"alert('\u{2}cream\u{3}')\"> & more"),
+            "&lt;img src=x onerror=&quot;alert(&#39;<mark>cream</mark>&#39;)&quot;&gt; &amp; more"
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use crate::*;
+
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+
+/// Cookie holding the session id of a signed in browser.
+const SESSION_COOKIE: &str = "rs_session";
+/// Cookie holding the CSRF token for forms shown before signing in.
+const CSRF_COOKIE: &str = "rs_csrf";
+const SESSION_LIFETIME: TimeDelta = TimeDelta::days(7);
+
+/// A signed in browser, with its account as it is now.
+pub struct Session {
+    id_hash: String,
+    pub user_id: i64,
+    pub full_name: String,
+    pub role: user::Role,
+    pub csrf_token: String,
+}
+
+fn cookie(name: &'static str, value: String) -> Cookie<'static> {
+    Cookie::build((name, value))
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .build()
+}
+
+/// Sign `user_id` in: store a new session and return `jar` with its cookie.
+/// The cookie lasts until the browser closes and the session at most
+/// [`SESSION_LIFETIME`]. Any CSRF cookie from before signing in is dropped,
+/// since forms now use the session's own token.
+pub async fn start(db: &SqlitePool, jar: CookieJar, user_id: i64) -> Result<CookieJar, sqlx::Error> {
+    let session_id = authjwt::random_token(32);
+    let csrf_token = authjwt::random_token(32);
+    let now = Utc::now();
+    let expires_at = (now + SESSION_LIFETIME).timestamp();
+    add(db, &authjwt::hash_token(&session_id), user_id, &csrf_token, expires_at, now.timestamp()).await?;
+    Ok(jar.add(cookie(SESSION_COOKIE, session_id)).remove(Cookie::build(CSRF_COOKIE).path("/")))
+}
+
+/// The session named by the cookie in `jar`, if it is live.
+pub async fn current(db: &SqlitePool, jar: &CookieJar) -> Option<Session> {
+    let session_id = jar.get(SESSION_COOKIE)?.value();
+    match get(db, &authjwt::hash_token(session_id), Utc::now().timestamp()).await {
+        Ok(session) => session,
+        Err(e) => {
+            log::warn!("session fetch failed: {}", e);
+            None
+        }
+    }
+}
+
+/// Sign out: forget `session` and return `jar` without its cookie.
+pub async fn end(db: &SqlitePool, jar: CookieJar, session: &Session) -> Result<CookieJar, sqlx::Error> {
+    delete(db, &session.id_hash).await?;
+    Ok(jar.remove(Cookie::build(SESSION_COOKIE).path("/")))
+}
+
+/// The CSRF token for a form: the session's own, or before signing in one
+/// kept in a cookie of its own, added to `jar` if it is new.
+pub fn csrf_token(session: Option<&Session>, jar: CookieJar) -> (String, CookieJar) {
+    if let Some(session) = session {
+        return (session.csrf_token.clone(), jar);
+    }
+    if let Some(token) = jar.get(CSRF_COOKIE) {
+        return (token.value().to_string(), jar);
+    }
+    let token = authjwt::random_token(32);
+    (token.clone(), jar.add(cookie(CSRF_COOKIE, token)))
+}
+
+/// Whether `submitted` is the CSRF token [`csrf_token`] handed out for
+/// this browser.
+pub fn check_csrf(session: Option<&Session>, jar: &CookieJar, submitted: &str) -> bool {
+    let expected = match session {
+        Some(session) => Some(session.csrf_token.as_str()),
+        None => jar.get(CSRF_COOKIE).map(|cookie| cookie.value()),
+    };
+    expected.is_some_and(|expected| !expected.is_empty() && expected == submitted)
+}
+
+/// Store a session, clearing out expired ones.
+async fn add(
+    db: &SqlitePool,
+    id_hash: &str,
+    user_id: i64,
+    csrf_token: &str,
+    expires_at: i64,
+    now: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM sessions WHERE expires_at < $1;", now)
+        .execute(db)
+        .await?;
+    sqlx::query!(
+        r#"INSERT INTO sessions (id_hash, user_id, csrf_token, expires_at)
+        VALUES ($1, $2, $3, $4);"#,
+        id_hash,
+        user_id,
+        csrf_token,
+        expires_at,
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+async fn get(db: &SqlitePool, id_hash: &str, now: i64) -> Result<Option<Session>, sqlx::Error> {
+    sqlx::query_as!(
+        Session,
+        r#"SELECT s.id_hash, s.user_id, u.full_name, u.role AS "role: user::Role", s.csrf_token
+        FROM sessions s JOIN users u ON u.id = s.user_id
+        WHERE s.id_hash = $1 AND s.expires_at > $2;"#,
+        id_hash,
+        now,
+    )
+    .fetch_optional(db)
+    .await
+}
+
+async fn delete(db: &SqlitePool, id_hash: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM sessions WHERE id_hash = $1;", id_hash)
+        .execute(db)
+        .await?;
+    Ok(())
+}
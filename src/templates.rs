@@ -7,15 +7,59 @@ use askama::Template;
 pub struct IndexTemplate {
     recipe: Recipe,
     stylesheet: &'static str,
-    ingredients: String
+    ingredients: String,
+    session: Option<session::Session>,
 }
 
 impl IndexTemplate {
-    pub fn new(recipe: Recipe, ingredients: String) -> Self {
+    pub fn new(recipe: Recipe, ingredients: String, session: Option<session::Session>) -> Self {
         Self {
             recipe,
             stylesheet: "style.css",
             ingredients,
+            session,
+        }
+    }
+
+    fn can_add(&self) -> bool {
+        self.session.as_ref().is_some_and(|session| session.role >= user::Role::Contributor)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "login.html")]
+pub struct LoginTemplate {
+    stylesheet: &'static str,
+    csrf_token: String,
+    error: Option<&'static str>,
+}
+
+impl LoginTemplate {
+    pub fn new(csrf_token: String, error: Option<&'static str>) -> Self {
+        Self {
+            stylesheet: "style.css",
+            csrf_token,
+            error,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "add-recipe.html")]
+pub struct AddRecipeTemplate {
+    stylesheet: &'static str,
+    csrf_token: String,
+    form: web::RecipeForm,
+    error: Option<&'static str>,
+}
+
+impl AddRecipeTemplate {
+    pub fn new(csrf_token: String, form: web::RecipeForm, error: Option<&'static str>) -> Self {
+        Self {
+            stylesheet: "style.css",
+            csrf_token,
+            form,
+            error,
         }
     }
 }
@@ -0,0 +1,55 @@
+//! Setup shared by the unit tests of several modules.
+
+use crate::*;
+
+use std::path::{Path, PathBuf};
+
+/// A fresh in-memory database with all migrations run.
+pub async fn test_db() -> SqlitePool {
+    let options = sqlite::SqliteConnectOptions::from_str("sqlite::memory:").unwrap().foreign_keys(true);
+    let db = sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&db).await.unwrap();
+    db
+}
+
+/// A fresh, empty key directory under the system temporary directory.
+pub fn key_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("jwt-keys-{}", authjwt::random_token(9)));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Write an Ed25519 key named `kid` to `dir`, made `age` ago.
+pub fn add_key(dir: &Path, kid: &str, age: TimeDelta) {
+    use ed25519_dalek::pkcs8::{EncodePrivateKey, spki::der::pem::LineEnding};
+
+    let key = ed25519_dalek::SigningKey::generate(&mut rand::rngs::OsRng);
+    let path = dir.join(format!("{}.pem", kid));
+    std::fs::write(&path, key.to_pkcs8_pem(LineEnding::LF).unwrap().as_bytes()).unwrap();
+    let file = std::fs::File::options().write(true).open(&path).unwrap();
+    file.set_modified((Utc::now() - age).into()).unwrap();
+}
+
+/// The keys `dir` is loaded with, which is then removed.
+pub async fn load_keys(dir: &Path) -> jwtkeys::JwtKeys {
+    let keys = jwtkeys::JwtKeys::load(dir, TimeDelta::minutes(16)).await.unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+    keys
+}
+
+/// State around a fresh database, with a new signing key and 15 minute
+/// tokens allowed a minute of leeway.
+pub async fn test_appstate() -> AppState {
+    let token_config = authjwt::TokenConfig {
+        issuer: "test-issuer".to_string(),
+        audience: "test-audience".to_string(),
+        lifetime: TimeDelta::minutes(15),
+        leeway: 60,
+    };
+    let jwt_keys = load_keys(&key_dir()).await;
+    AppState::new(test_db().await, token_config, jwt_keys, None, vec![])
+}
@@ -0,0 +1,271 @@
+use crate::*;
+
+use std::net::IpAddr;
+
+/// Failures allowed against one account before it is locked out.
+const ACCOUNT_FREE_FAILURES: i64 = 5;
+/// Failures allowed from one address before it is locked out. Higher than
+/// for accounts since many users can share an address.
+const IP_FREE_FAILURES: i64 = 20;
+/// First lockout; each further failure doubles it up to [`MAX_LOCKOUT`].
+const BASE_LOCKOUT: TimeDelta = TimeDelta::seconds(30);
+const MAX_LOCKOUT: TimeDelta = TimeDelta::hours(1);
+/// Failures are forgotten once there has been none for this long.
+const FORGET_AFTER: TimeDelta = TimeDelta::days(1);
+
+/// What failed attempts are counted against.
+pub enum Key<'a> {
+    Ip(IpAddr),
+    /// An email as typed at login, whether or not it has an account.
+    Account(&'a str),
+    User(i64),
+}
+
+impl std::fmt::Display for Key<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Key::Ip(ip) => write!(f, "ip:{}", ip),
+            Key::Account(email) => write!(f, "account:{}", email.trim().to_lowercase()),
+            Key::User(user_id) => write!(f, "user:{}", user_id),
+        }
+    }
+}
+
+impl Key<'_> {
+    fn free_failures(&self) -> i64 {
+        match self {
+            Key::Ip(_) => IP_FREE_FAILURES,
+            Key::Account(_) | Key::User(_) => ACCOUNT_FREE_FAILURES,
+        }
+    }
+}
+
+/// Address of the client making the request, for [`Key::Ip`]. This is the
+/// peer address unless the peer is a trusted proxy (`--trusted-proxy`), in
+/// which case it is the nearest address in `X-Forwarded-For` not also a
+/// trusted proxy. Without trusted proxies every client behind a proxy
+/// shares the proxy's address.
+pub struct ClientIp(pub IpAddr);
+
+impl axum::extract::FromRequestParts<SharedAppState> for ClientIp {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &SharedAppState,
+    ) -> Result<Self, Self::Rejection> {
+        let peer = match parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(addr)) => addr.ip(),
+            None => IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+        };
+        let forwarded_for: Vec<&str> = parts
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        let trusted = &state.read().await.trusted_proxies;
+        Ok(Self(client_ip(peer, &forwarded_for.join(","), trusted)))
+    }
+}
+
+fn client_ip(peer: IpAddr, forwarded_for: &str, trusted: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    // Each proxy appends the address it heard from, so walk back from the
+    // end while the hops are ones we trust.
+    for hop in forwarded_for.rsplit(',') {
+        if !trusted.contains(&client) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+/// Count an attempt against each of `keys` before it is made, refusing it
+/// while any of them is locked out and saying how long to wait. The attempt
+/// that uses up the free failures of a key locks it until the attempt is
+/// given back, so concurrent attempts cannot get past the limit; further
+/// attempts double the lockout up to [`MAX_LOCKOUT`]. Attempts that did not
+/// fail are given back with [`release`] or [`record_success`].
+pub async fn reserve(db: &SqlitePool, keys: &[Key<'_>]) -> Result<(), authjwt::AuthError> {
+    let now = Utc::now().timestamp();
+    for (reserved, key) in keys.iter().enumerate() {
+        let name = key.to_string();
+        let counted = match add_attempt(db, &name, key.free_failures(), now, now - FORGET_AFTER.num_seconds()).await {
+            Ok(counted) => counted,
+            Err(e) => {
+                release(db, &keys[..reserved]).await;
+                return Err(authjwt::storage_error(e));
+            }
+        };
+        let Some((failures, locked_until)) = counted else {
+            release(db, &keys[..reserved]).await;
+            return Err(authjwt::AuthError::TooManyAttempts(wait(db, keys, now).await?));
+        };
+        if let Some(locked_until) = locked_until {
+            tracing::warn!(key = %name, failures, lockout_seconds = locked_until - now, "authentication locked out");
+        }
+    }
+    Ok(())
+}
+
+/// Seconds until none of `keys` is locked out.
+async fn wait(db: &SqlitePool, keys: &[Key<'_>], now: i64) -> Result<u64, authjwt::AuthError> {
+    let mut wait = 1;
+    for key in keys {
+        let locked_until = locked_until(db, &key.to_string())
+            .await
+            .map_err(authjwt::storage_error)?;
+        if let Some(locked_until) = locked_until {
+            wait = wait.max(locked_until - now);
+        }
+    }
+    Ok(u64::try_from(wait).unwrap_or(1))
+}
+
+/// Give back the attempt reserved against `keys`, for an attempt that did
+/// not fail.
+pub async fn release(db: &SqlitePool, keys: &[Key<'_>]) {
+    for key in keys {
+        let name = key.to_string();
+        if let Err(e) = remove_attempt(db, &name, key.free_failures()).await {
+            log::warn!("auth attempt release failed: {}: {}", name, e);
+        }
+    }
+}
+
+/// Forget the failures against `keys` after a successful attempt. Addresses
+/// only get this attempt back, their failures being left to be forgotten in
+/// time, so an attacker cannot clear theirs by signing in to an account of
+/// their own.
+pub async fn record_success(db: &SqlitePool, keys: &[Key<'_>]) {
+    for key in keys {
+        if let Key::Ip(_) = key {
+            release(db, std::slice::from_ref(key)).await;
+            continue;
+        }
+        let name = key.to_string();
+        if let Err(e) = clear(db, &name).await {
+            log::warn!("auth failure reset failed: {}: {}", name, e);
+        }
+    }
+}
+
+async fn locked_until(db: &SqlitePool, key: &str) -> Result<Option<i64>, sqlx::Error> {
+    let locked_until = sqlx::query_scalar!("SELECT locked_until FROM auth_failures WHERE key = $1;", key)
+        .fetch_optional(db)
+        .await?;
+    Ok(locked_until.flatten())
+}
+
+/// Count one more attempt unless the key is locked out, starting over if
+/// the last one was before `forget_before`, and lock it out once `free` are
+/// used up. Returns the count and lockout, or `None` if it was locked out.
+async fn add_attempt(
+    db: &SqlitePool,
+    key: &str,
+    free: i64,
+    now: i64,
+    forget_before: i64,
+) -> Result<Option<(i64, Option<i64>)>, sqlx::Error> {
+    let base = BASE_LOCKOUT.num_seconds();
+    let max = MAX_LOCKOUT.num_seconds();
+    let counted = sqlx::query!(
+        r#"INSERT INTO auth_failures (key, failures, last_failure_at) VALUES ($1, 1, $2)
+        ON CONFLICT (key) DO UPDATE SET
+          failures = CASE WHEN last_failure_at < $3 THEN 1 ELSE failures + 1 END,
+          last_failure_at = excluded.last_failure_at,
+          locked_until = CASE WHEN last_failure_at >= $3 AND failures + 1 >= $4
+            THEN $2 + min($5 << min(failures + 1 - $4, 16), $6) END
+        WHERE locked_until IS NULL OR locked_until <= $2
+        RETURNING failures, locked_until;"#,
+        key,
+        now,
+        forget_before,
+        free,
+        base,
+        max,
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(counted.map(|counted| (counted.failures, counted.locked_until)))
+}
+
+/// Take back one attempt, lifting the lockout if that leaves free failures.
+async fn remove_attempt(db: &SqlitePool, key: &str, free: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE auth_failures SET
+          failures = failures - 1,
+          locked_until = CASE WHEN failures - 1 < $2 THEN NULL ELSE locked_until END
+        WHERE key = $1 AND failures > 0;"#,
+        key,
+        free,
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+async fn clear(db: &SqlitePool, key: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM auth_failures WHERE key = $1;", key)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reservations_lock_out_once_free_failures_are_used() {
+        let db = crate::testing::test_db().await;
+        let keys = [Key::User(1)];
+        for _ in 0..ACCOUNT_FREE_FAILURES {
+            reserve(&db, &keys).await.unwrap();
+        }
+        assert!(matches!(reserve(&db, &keys).await, Err(authjwt::AuthError::TooManyAttempts(29..=30))));
+
+        release(&db, &keys).await;
+        reserve(&db, &keys).await.unwrap();
+        sqlx::query("UPDATE auth_failures SET locked_until = 0;").execute(&db).await.unwrap();
+        reserve(&db, &keys).await.unwrap();
+        assert!(matches!(reserve(&db, &keys).await, Err(authjwt::AuthError::TooManyAttempts(59..=60))));
+
+        record_success(&db, &keys).await;
+        reserve(&db, &keys).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refused_reservation_gives_back_the_others() {
+        let db = crate::testing::test_db().await;
+        let ip = Key::Ip("203.0.113.7".parse().unwrap());
+        for _ in 0..ACCOUNT_FREE_FAILURES {
+            reserve(&db, &[Key::Account("jo@example.org")]).await.unwrap();
+        }
+        let refused = reserve(&db, &[ip, Key::Account("jo@example.org")]).await;
+        assert!(matches!(refused, Err(authjwt::AuthError::TooManyAttempts(_))));
+        let ip_failures: Option<i64> = sqlx::query_scalar("SELECT failures FROM auth_failures WHERE key LIKE 'ip:%';")
+            .fetch_optional(&db)
+            .await
+            .unwrap();
+        assert_eq!(ip_failures, Some(0));
+    }
+
+    #[test]
+    fn client_ip_trusts_forwarded_for_only_from_trusted_proxies() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+        assert_eq!(client_ip(ip("203.0.113.7"), "198.51.100.1", &proxies), ip("203.0.113.7"));
+        assert_eq!(client_ip(ip("10.0.0.1"), "", &proxies), ip("10.0.0.1"));
+        assert_eq!(client_ip(ip("10.0.0.1"), "198.51.100.1", &proxies), ip("198.51.100.1"));
+        assert_eq!(client_ip(ip("10.0.0.1"), "6.6.6.6, 198.51.100.1, 10.0.0.2", &proxies), ip("198.51.100.1"));
+        assert_eq!(client_ip(ip("10.0.0.1"), "198.51.100.1, junk", &proxies), ip("10.0.0.1"));
+        assert_eq!(client_ip(ip("10.0.0.1"), "2001:db8::1", &proxies), ip("2001:db8::1"));
+        assert_eq!(client_ip(ip("10.0.0.1"), "198.51.100.1", &[]), ip("10.0.0.1"));
+    }
+}
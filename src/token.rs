@@ -0,0 +1,103 @@
+use crate::*;
+
+pub struct RefreshToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub family: String,
+    pub expires_at: i64,
+}
+
+pub async fn add_refresh(
+    db: &SqlitePool,
+    user_id: i64,
+    token_hash: &str,
+    family: &str,
+    expires_at: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO refresh_tokens (user_id, token_hash, family, expires_at)
+        VALUES ($1, $2, $3, $4);"#,
+        user_id,
+        token_hash,
+        family,
+        expires_at,
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Look up a refresh token by hash. The second value is `false` if the token
+/// has already been used or revoked.
+pub async fn get_refresh(db: &SqlitePool, token_hash: &str) -> Result<Option<(RefreshToken, bool)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT id, user_id, family, expires_at, revoked_at IS NULL AS "live!: bool"
+        FROM refresh_tokens WHERE token_hash = $1;"#,
+        token_hash,
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(row.map(|row| {
+        let token = RefreshToken {
+            id: row.id,
+            user_id: row.user_id,
+            family: row.family,
+            expires_at: row.expires_at,
+        };
+        (token, row.live)
+    }))
+}
+
+/// Mark one refresh token used. Returns `false` if it was not live, so two
+/// requests racing with the same token cannot both succeed.
+pub async fn revoke_refresh(db: &SqlitePool, id: i64, now: i64) -> Result<bool, sqlx::Error> {
+    let revoked = sqlx::query!(
+        "UPDATE refresh_tokens SET revoked_at = $1 WHERE id = $2 AND revoked_at IS NULL;",
+        now,
+        id,
+    )
+    .execute(db)
+    .await?;
+    Ok(revoked.rows_affected() > 0)
+}
+
+/// Revoke every refresh token descended from the same login, and drop
+/// refresh tokens that have expired.
+pub async fn revoke_family(db: &SqlitePool, family: &str, now: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE refresh_tokens SET revoked_at = $1 WHERE family = $2 AND revoked_at IS NULL;",
+        now,
+        family,
+    )
+    .execute(db)
+    .await?;
+    sqlx::query!("DELETE FROM refresh_tokens WHERE expires_at < $1;", now)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Refuse the access token `jti` from now until it expires, and forget
+/// revocations of tokens that have expired.
+pub async fn revoke_access(db: &SqlitePool, jti: &str, expires_at: i64, now: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT OR IGNORE INTO revoked_tokens (jti, expires_at) VALUES ($1, $2);",
+        jti,
+        expires_at,
+    )
+    .execute(db)
+    .await?;
+    sqlx::query!("DELETE FROM revoked_tokens WHERE expires_at < $1;", now)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+pub async fn is_revoked(db: &SqlitePool, jti: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM revoked_tokens WHERE jti = $1) AS "revoked!: bool";"#,
+        jti,
+    )
+    .fetch_one(db)
+    .await
+}
@@ -0,0 +1,402 @@
+use crate::*;
+
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use sqlx::Connection;
+
+const STEP_SECONDS: i64 = 30;
+const DIGITS: usize = 6;
+/// Steps either side of the current one a code may come from, to allow for
+/// clock drift.
+const SKEW_STEPS: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Account issuer shown by authenticator apps.
+const ISSUER: &str = "recipe-server";
+
+/// A started enrollment: the secret to add to an authenticator app, as
+/// text, as an `otpauth://` URI and as a QR code of that URI.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Enrollment {
+    #[schema(example = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP")]
+    secret: String,
+    #[schema(example = "otpauth://totp/recipe-server:johnsmith%40example.org?secret=JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP&issuer=recipe-server&algorithm=SHA1&digits=6&period=30")]
+    otpauth_uri: String,
+    /// SVG image of `otpauth_uri` as a QR code.
+    qr_code_svg: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Confirmation {
+    /// Current code shown by the authenticator app.
+    #[schema(example = "123456")]
+    code: String,
+}
+
+/// One-time codes for signing in without the authenticator. They are not
+/// stored and cannot be shown again.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RecoveryCodes {
+    #[schema(example = json!(["k3mx-q7va-z4hc-81pd", "p2dn-w8rt-b6ye-5jqa"]))]
+    recovery_codes: Vec<String>,
+}
+
+// RFC 6238 with the parameters authenticator apps assume: HMAC-SHA1, six
+// digits, 30 second steps.
+fn code_at(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<sha1::Sha1>::new_from_slice(secret).expect("hmac takes keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+    let value = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    value % 10u32.pow(DIGITS as u32)
+}
+
+/// The time step near `now` at which `secret` gives `code`, if any.
+fn matching_step(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    if code.len() != DIGITS {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = now / STEP_SECONDS;
+    (current - SKEW_STEPS..=current + SKEW_STEPS).find(|&step| code_at(&secret, step) == code)
+}
+
+// Codes are shown grouped but may be typed without the dash or spaces, in
+// either case.
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+// 80 random bits, too many to guess against a leaked fast hash, shown as
+// four groups of four.
+fn new_recovery_code() -> String {
+    use rand::RngCore;
+
+    let mut bytes = [0u8; 10];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    let code = BASE32_NOPAD.encode(&bytes).to_ascii_lowercase();
+    let groups: Vec<&str> = (0..code.len()).step_by(4).map(|i| &code[i..i + 4]).collect();
+    groups.join("-")
+}
+
+fn otpauth_uri(email: &str, secret: &str) -> Result<String, authjwt::AuthError> {
+    let mut uri = reqwest::Url::parse("otpauth://totp/").map_err(|_| authjwt::AuthError::Storage)?;
+    uri.set_path(&format!("{}:{}", ISSUER, email));
+    uri.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", ISSUER)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP_SECONDS.to_string());
+    Ok(uri.into())
+}
+
+/// Start enrolling the caller with a new secret, replacing any enrollment
+/// that was never confirmed.
+pub async fn enroll(appstate: &AppState, claims: &authjwt::Claims) -> Result<Enrollment, authjwt::AuthError> {
+    use rand::RngCore;
+
+    claims.require_session()?;
+    let user_id = claims.user_id()?;
+    let email = user::get_email(&appstate.db, user_id)
+        .await
+        .map_err(authjwt::storage_error)?
+        .ok_or(authjwt::AuthError::InvalidToken)?;
+
+    let mut bytes = [0u8; 20];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    let secret = BASE32_NOPAD.encode(&bytes);
+    if !set_pending(&appstate.db, user_id, &secret).await.map_err(authjwt::storage_error)? {
+        return Err(authjwt::AuthError::TotpEnabled);
+    }
+
+    let otpauth_uri = otpauth_uri(&email, &secret)?;
+    let qr_code_svg = qrcode::QrCode::new(otpauth_uri.as_bytes())
+        .map_err(|_| authjwt::AuthError::Storage)?
+        .render::<qrcode::render::svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+    Ok(Enrollment { secret, otpauth_uri, qr_code_svg })
+}
+
+/// Finish enrolling the caller with a first code from the authenticator,
+/// turning on the second factor, and return new recovery codes. Wrong codes
+/// count as failures against the account.
+pub async fn confirm(
+    appstate: &AppState,
+    claims: &authjwt::Claims,
+    confirmation: &Confirmation,
+    request_id: Option<&str>,
+) -> Result<RecoveryCodes, authjwt::AuthError> {
+    claims.require_session()?;
+    let user_id = claims.user_id()?;
+    let totp = get(&appstate.db, user_id)
+        .await
+        .map_err(authjwt::storage_error)?
+        .ok_or(authjwt::AuthError::TotpNotEnrolled)?;
+    if totp.confirmed_at.is_some() {
+        return Err(authjwt::AuthError::TotpEnabled);
+    }
+    let keys = [throttle::Key::User(user_id)];
+    throttle::reserve(&appstate.db, &keys).await?;
+    let now = Utc::now().timestamp();
+    let Some(step) = matching_step(&totp.secret, &normalize_code(&confirmation.code), now) else {
+        return Err(authjwt::AuthError::InvalidTotpCode);
+    };
+    throttle::record_success(&appstate.db, &keys).await;
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| new_recovery_code()).collect();
+    let code_hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| authjwt::hash_token(&normalize_code(code)))
+        .collect();
+    let mut tx = appstate.db.begin().await.map_err(authjwt::storage_error)?;
+    if !enable(&mut tx, user_id, step, now, &code_hashes).await.map_err(authjwt::storage_error)? {
+        return Err(authjwt::AuthError::TotpEnabled);
+    }
+    let event = audit::Event::new(audit::Action::TotpEnable, request_id)
+        .actor(Some(user_id))
+        .target(user_id);
+    audit::write(&mut *tx, event).await.map_err(authjwt::storage_error)?;
+    tx.commit().await.map_err(authjwt::storage_error)?;
+    Ok(RecoveryCodes { recovery_codes })
+}
+
+/// Turn off the caller's second factor. Takes a current code from the
+/// authenticator, so a stolen session alone cannot do it; wrong codes count
+/// as failures against the account.
+pub async fn disable(
+    appstate: &AppState,
+    claims: &authjwt::Claims,
+    confirmation: &Confirmation,
+    request_id: Option<&str>,
+) -> Result<(), authjwt::AuthError> {
+    claims.require_session()?;
+    let user_id = claims.user_id()?;
+    let totp = get(&appstate.db, user_id)
+        .await
+        .map_err(authjwt::storage_error)?
+        .filter(|totp| totp.confirmed_at.is_some())
+        .ok_or(authjwt::AuthError::TotpNotEnabled)?;
+    let keys = [throttle::Key::User(user_id)];
+    throttle::reserve(&appstate.db, &keys).await?;
+    let now = Utc::now().timestamp();
+    let accepted = match matching_step(&totp.secret, &normalize_code(&confirmation.code), now) {
+        Some(step) => use_step(&appstate.db, user_id, step).await,
+        None => Ok(false),
+    };
+    match accepted {
+        Ok(true) => (),
+        Ok(false) => return Err(authjwt::AuthError::InvalidTotpCode),
+        Err(e) => {
+            throttle::release(&appstate.db, &keys).await;
+            return Err(authjwt::storage_error(e));
+        }
+    }
+    throttle::record_success(&appstate.db, &keys).await;
+
+    let mut tx = appstate.db.begin().await.map_err(authjwt::storage_error)?;
+    remove(&mut tx, user_id).await.map_err(authjwt::storage_error)?;
+    let event = audit::Event::new(audit::Action::TotpDisable, request_id)
+        .actor(Some(user_id))
+        .target(user_id);
+    audit::write(&mut *tx, event).await.map_err(authjwt::storage_error)?;
+    tx.commit().await.map_err(authjwt::storage_error)
+}
+
+/// Check the second factor of an account whose password was right. Passes
+/// if it has none; otherwise `code` must be a code from its authenticator
+/// not used before, or one of its unused recovery codes.
+pub async fn check(db: &SqlitePool, user_id: i64, code: Option<&str>) -> Result<(), authjwt::AuthError> {
+    let Some(totp) = get(db, user_id).await.map_err(authjwt::storage_error)? else {
+        return Ok(());
+    };
+    if totp.confirmed_at.is_none() {
+        return Ok(());
+    }
+    let code = normalize_code(code.unwrap_or_default());
+    if code.is_empty() {
+        return Err(authjwt::AuthError::TotpRequired);
+    }
+
+    let now = Utc::now().timestamp();
+    let accepted = match matching_step(&totp.secret, &code, now) {
+        Some(step) => use_step(db, user_id, step).await,
+        None => use_recovery_code(db, user_id, &authjwt::hash_token(&code), now).await,
+    };
+    match accepted.map_err(authjwt::storage_error)? {
+        true => Ok(()),
+        false => Err(authjwt::AuthError::WrongCredentials),
+    }
+}
+
+/// Whether the account has a confirmed authenticator.
+pub async fn enabled(db: &SqlitePool, user_id: i64) -> Result<bool, authjwt::AuthError> {
+    let totp = get(db, user_id).await.map_err(authjwt::storage_error)?;
+    Ok(totp.is_some_and(|totp| totp.confirmed_at.is_some()))
+}
+
+struct UserTotp {
+    secret: String,
+    confirmed_at: Option<i64>,
+}
+
+async fn get(db: &SqlitePool, user_id: i64) -> Result<Option<UserTotp>, sqlx::Error> {
+    sqlx::query_as!(
+        UserTotp,
+        "SELECT secret, confirmed_at FROM user_totp WHERE user_id = $1;",
+        user_id,
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Store an unconfirmed secret. Returns `false` if the account already has
+/// a confirmed one, which is left alone.
+async fn set_pending(db: &SqlitePool, user_id: i64, secret: &str) -> Result<bool, sqlx::Error> {
+    let stored = sqlx::query!(
+        r#"INSERT INTO user_totp (user_id, secret) VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE SET secret = excluded.secret
+        WHERE confirmed_at IS NULL;"#,
+        user_id,
+        secret,
+    )
+    .execute(db)
+    .await?;
+    Ok(stored.rows_affected() > 0)
+}
+
+/// Confirm the pending secret, with `step` as its first used code, and
+/// replace the account's recovery codes. Returns `false` if it was
+/// confirmed already.
+async fn enable(
+    conn: &mut sqlx::SqliteConnection,
+    user_id: i64,
+    step: i64,
+    now: i64,
+    code_hashes: &[String],
+) -> Result<bool, sqlx::Error> {
+    let mut jtx = conn.begin().await?;
+    let confirmed = sqlx::query!(
+        r#"UPDATE user_totp SET confirmed_at = $1, last_step = $2
+        WHERE user_id = $3 AND confirmed_at IS NULL;"#,
+        now,
+        step,
+        user_id,
+    )
+    .execute(&mut *jtx)
+    .await?;
+    if confirmed.rows_affected() == 0 {
+        return Ok(false);
+    }
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1;", user_id)
+        .execute(&mut *jtx)
+        .await?;
+    for code_hash in code_hashes {
+        sqlx::query!(
+            "INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2);",
+            user_id,
+            code_hash,
+        )
+        .execute(&mut *jtx)
+        .await?;
+    }
+    jtx.commit().await?;
+    Ok(true)
+}
+
+/// Drop the account's authenticator, enabled or pending, and its recovery
+/// codes. Returns `false` if it had no authenticator.
+pub async fn remove(conn: &mut sqlx::SqliteConnection, user_id: i64) -> Result<bool, sqlx::Error> {
+    let mut rtx = conn.begin().await?;
+    let removed = sqlx::query!("DELETE FROM user_totp WHERE user_id = $1;", user_id)
+        .execute(&mut *rtx)
+        .await?;
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1;", user_id)
+        .execute(&mut *rtx)
+        .await?;
+    rtx.commit().await?;
+    Ok(removed.rows_affected() > 0)
+}
+
+/// Accept a code from time step `step`. Returns `false` if a code from that
+/// step or a later one was already used.
+async fn use_step(db: &SqlitePool, user_id: i64, step: i64) -> Result<bool, sqlx::Error> {
+    let used = sqlx::query!(
+        r#"UPDATE user_totp SET last_step = $1
+        WHERE user_id = $2 AND (last_step IS NULL OR last_step < $1);"#,
+        step,
+        user_id,
+    )
+    .execute(db)
+    .await?;
+    Ok(used.rows_affected() > 0)
+}
+
+/// Use up a recovery code. Returns `false` if there is no such unused code.
+async fn use_recovery_code(db: &SqlitePool, user_id: i64, code_hash: &str, now: i64) -> Result<bool, sqlx::Error> {
+    let used = sqlx::query!(
+        r#"UPDATE recovery_codes SET used_at = $1
+        WHERE user_id = $2 AND code_hash = $3 AND used_at IS NULL;"#,
+        now,
+        user_id,
+        code_hash,
+    )
+    .execute(db)
+    .await?;
+    if used.rows_affected() > 0 {
+        log::info!("user {} signed in with a recovery code", user_id);
+    }
+    Ok(used.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B, SHA1 column, keeping the low six of its eight
+    // digits.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+    const RFC_VECTORS: &[(i64, u32)] = &[
+        (59, 287082),
+        (1111111109, 81804),
+        (1111111111, 50471),
+        (1234567890, 5924),
+        (2000000000, 279037),
+        (20000000000, 353130),
+    ];
+
+    #[test]
+    fn code_at_matches_rfc_6238() {
+        for &(time, code) in RFC_VECTORS {
+            assert_eq!(code_at(RFC_SECRET, time / STEP_SECONDS), code, "at time {}", time);
+        }
+    }
+
+    #[test]
+    fn matching_step_allows_one_step_of_drift() {
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        let now = 1111111109;
+        let step = now / STEP_SECONDS;
+        let code = |step| format!("{:06}", code_at(RFC_SECRET, step));
+        assert_eq!(matching_step(&secret, &code(step), now), Some(step));
+        assert_eq!(matching_step(&secret, &code(step - 1), now), Some(step - 1));
+        assert_eq!(matching_step(&secret, &code(step + 1), now), Some(step + 1));
+        assert_eq!(matching_step(&secret, &code(step + 2), now), None);
+        assert_eq!(matching_step(&secret, "81804", now), None);
+    }
+
+    #[test]
+    fn recovery_codes_carry_80_bits() {
+        let code = new_recovery_code();
+        assert_eq!(code.len(), 19);
+        assert_eq!(code.split('-').count(), 4);
+        assert_eq!(BASE32_NOPAD.decode(normalize_code(&code).to_ascii_uppercase().as_bytes()).unwrap().len(), 10);
+        assert_ne!(code, new_recovery_code());
+        assert_eq!(normalize_code(" K3MX-q7va "), "k3mxq7va");
+    }
+}
@@ -0,0 +1,276 @@
+use crate::*;
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UnitSystem {
+    Metric,
+    Imperial,
+}
+
+impl fmt::Display for UnitSystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnitSystem::Metric => write!(f, "metric"),
+            UnitSystem::Imperial => write!(f, "imperial"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Dimension {
+    Mass,
+    Volume,
+}
+
+// Unit, what it measures, its size in grams or milliliters, and the system it
+// belongs to. Spoons and cups are US customary measures.
+const CONVERSIONS: &[(&str, Dimension, f64, UnitSystem)] = &[
+    ("mg", Dimension::Mass, 0.001, UnitSystem::Metric),
+    ("g", Dimension::Mass, 1.0, UnitSystem::Metric),
+    ("kg", Dimension::Mass, 1000.0, UnitSystem::Metric),
+    ("oz", Dimension::Mass, 28.349523125, UnitSystem::Imperial),
+    ("lb", Dimension::Mass, 453.59237, UnitSystem::Imperial),
+    ("ml", Dimension::Volume, 1.0, UnitSystem::Metric),
+    ("cl", Dimension::Volume, 10.0, UnitSystem::Metric),
+    ("dl", Dimension::Volume, 100.0, UnitSystem::Metric),
+    ("l", Dimension::Volume, 1000.0, UnitSystem::Metric),
+    ("tsp", Dimension::Volume, 4.92892159375, UnitSystem::Imperial),
+    ("tbsp", Dimension::Volume, 14.78676478125, UnitSystem::Imperial),
+    ("fl oz", Dimension::Volume, 29.5735295625, UnitSystem::Imperial),
+    ("cup", Dimension::Volume, 236.5882365, UnitSystem::Imperial),
+    ("pt", Dimension::Volume, 473.176473, UnitSystem::Imperial),
+    ("qt", Dimension::Volume, 946.352946, UnitSystem::Imperial),
+    ("gal", Dimension::Volume, 3785.411784, UnitSystem::Imperial),
+];
+
+fn lookup(unit: &str) -> Option<(&'static str, Dimension, f64, UnitSystem)> {
+    CONVERSIONS.iter().find(|(name, ..)| *name == unit).copied()
+}
+
+/// The unit a cook in `system` would use for `base` grams or milliliters.
+fn preferred_unit(dimension: Dimension, base: f64, system: UnitSystem) -> &'static str {
+    match (system, dimension) {
+        (UnitSystem::Metric, Dimension::Mass) if base >= 1000.0 => "kg",
+        (UnitSystem::Metric, Dimension::Mass) => "g",
+        (UnitSystem::Metric, Dimension::Volume) if base >= 1000.0 => "l",
+        (UnitSystem::Metric, Dimension::Volume) => "ml",
+        (UnitSystem::Imperial, Dimension::Mass) if base >= 453.0 => "lb",
+        (UnitSystem::Imperial, Dimension::Mass) => "oz",
+        (UnitSystem::Imperial, Dimension::Volume) if base >= 59.0 => "cup",
+        (UnitSystem::Imperial, Dimension::Volume) if base >= 14.0 => "tbsp",
+        (UnitSystem::Imperial, Dimension::Volume) => "tsp",
+    }
+}
+
+/// The next smaller unit to measure with when `unit` cannot express an
+/// amount closely enough.
+fn smaller_unit(unit: &str) -> Option<&'static str> {
+    match unit {
+        "kg" => Some("g"),
+        "l" => Some("ml"),
+        "lb" => Some("oz"),
+        "cup" => Some("tbsp"),
+        "tbsp" => Some("tsp"),
+        _ => None,
+    }
+}
+
+/// How far rounding may move a quantity, as a share of it, before a smaller
+/// unit is tried instead.
+const ROUNDING_TOLERANCE: f64 = 0.1;
+
+fn round_for(unit: &str, quantity: f64, system: UnitSystem) -> f64 {
+    match system {
+        UnitSystem::Metric if matches!(unit, "kg" | "l") => (quantity * 100.0).round() / 100.0,
+        UnitSystem::Metric if quantity >= 10.0 => quantity.round(),
+        UnitSystem::Metric => (quantity * 10.0).round() / 10.0,
+        UnitSystem::Imperial if quantity >= 1.0 => (quantity * 4.0).round() / 4.0,
+        UnitSystem::Imperial => (quantity * 8.0).round() / 8.0,
+    }
+}
+
+/// Convert `quantity` (and the upper end of a range) given in `unit` into
+/// the preferred unit of `system`, or a smaller one if rounding to the
+/// preferred unit would change the amount too much. Returns `None` for units
+/// that are already in `system` or that have no conversion, such as "pinch"
+/// or "clove", and for amounts too small for any unit of `system` to hold
+/// closely, such as 3 g in ounces.
+pub fn convert(
+    quantity: f64,
+    quantity_max: Option<f64>,
+    unit: &str,
+    system: UnitSystem,
+) -> Option<(f64, Option<f64>, &'static str)> {
+    let (_, dimension, size, from) = lookup(unit)?;
+    if from == system {
+        return None;
+    }
+    let close = |rounded: f64, exact: f64| (rounded - exact).abs() <= exact * ROUNDING_TOLERANCE;
+    let mut target = preferred_unit(dimension, quantity * size, system);
+    loop {
+        let (_, _, target_size, _) = lookup(target)?;
+        let factor = size / target_size;
+        let rounded = round_for(target, quantity * factor, system);
+        let rounded_max = quantity_max.map(|max| round_for(target, max * factor, system));
+        let fits = close(rounded, quantity * factor)
+            && rounded_max.zip(quantity_max).is_none_or(|(rounded, max)| close(rounded, max * factor));
+        if fits {
+            return Some((rounded, rounded_max, target));
+        }
+        target = smaller_unit(target)?;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scale {
+    Celsius,
+    Fahrenheit,
+}
+
+/// Length of a temperature unit such as "°C", " degrees F" or
+/// " Fahrenheit" at the start of `s`, and which scale it names.
+fn parse_temperature_unit(s: &str) -> Option<(Scale, usize)> {
+    let t = s.trim_start_matches(' ');
+    let (marked, t) = if let Some(t) = t.strip_prefix(['°', 'º']) {
+        (true, t.trim_start_matches(' '))
+    } else if let Some(t) = t.strip_prefix("degrees ").or_else(|| t.strip_prefix("degree ")) {
+        (true, t)
+    } else {
+        (false, t)
+    };
+
+    let words = [
+        ("celsius", Scale::Celsius),
+        ("centigrade", Scale::Celsius),
+        ("fahrenheit", Scale::Fahrenheit),
+    ];
+    let lower = t.to_lowercase();
+    let (scale, len) = if let Some((word, scale)) = words.iter().find(|(w, _)| lower.starts_with(w)) {
+        (*scale, word.len())
+    } else if marked || t.len() == s.len() {
+        // A lone letter only counts right after the number or a degree sign.
+        match t.chars().next() {
+            Some('C') => (Scale::Celsius, 1),
+            Some('c') if marked => (Scale::Celsius, 1),
+            Some('F') => (Scale::Fahrenheit, 1),
+            Some('f') if marked => (Scale::Fahrenheit, 1),
+            _ => return None,
+        }
+    } else {
+        return None;
+    };
+    if t[len..].starts_with(char::is_alphanumeric) {
+        return None;
+    }
+    Some((scale, s.len() - t.len() + len))
+}
+
+/// Length of the number at the start of `s`: digits with an optional
+/// decimal part, as in "180" or "180.5".
+fn number_len(s: &str) -> usize {
+    let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let whole = digits(s);
+    match s[whole..].strip_prefix('.') {
+        Some(fraction) if fraction.starts_with(|c: char| c.is_ascii_digit()) => whole + 1 + digits(fraction),
+        _ => whole,
+    }
+}
+
+/// Rewrite temperatures mentioned in free text, e.g. "bake at 180°C", into
+/// `system`. Oven temperatures, above the boiling point of water, are
+/// rounded to the nearest 5 degrees and others to whole degrees; text that
+/// does not look like a temperature is left as is.
+pub fn convert_temperatures(text: &str, system: UnitSystem) -> String {
+    let target = match system {
+        UnitSystem::Metric => Scale::Celsius,
+        UnitSystem::Imperial => Scale::Fahrenheit,
+    };
+    let mut converted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_digit()) {
+        let before = &rest[..start];
+        let before = before.strip_suffix(['-', '−']).unwrap_or(before);
+        converted.push_str(before);
+        let mut sign = &rest[before.len()..start];
+        // A minus sign counts unless it joins two words or numbers, as in "180-200".
+        if converted.ends_with(char::is_alphanumeric) {
+            converted.push_str(sign);
+            sign = "";
+        }
+        rest = &rest[start..];
+        let (number, after) = rest.split_at(number_len(rest));
+
+        match (number.parse::<f64>(), parse_temperature_unit(after)) {
+            (Ok(value), Some((scale, len))) if scale != target => {
+                let value = if sign.is_empty() { value } else { -value };
+                let (value, oven) = match target {
+                    Scale::Celsius => ((value - 32.0) * 5.0 / 9.0, value > 212.0),
+                    Scale::Fahrenheit => (value * 9.0 / 5.0 + 32.0, value > 100.0),
+                };
+                let value = if oven { (value / 5.0).round() * 5.0 } else { value.round() };
+                let unit = match target {
+                    Scale::Celsius => "°C",
+                    Scale::Fahrenheit => "°F",
+                };
+                converted.push_str(&format!("{}{}", value as i64, unit));
+                rest = &after[len..];
+            }
+            _ => {
+                converted.push_str(sign);
+                converted.push_str(number);
+                rest = after;
+            }
+        }
+    }
+    converted.push_str(rest);
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_picks_unit_of_other_system() {
+        assert_eq!(convert(2.0, None, "cup", UnitSystem::Metric), Some((473.0, None, "ml")));
+        assert_eq!(convert(1.0, Some(2.0), "lb", UnitSystem::Metric), Some((454.0, Some(907.0), "g")));
+        assert_eq!(convert(1500.0, None, "g", UnitSystem::Imperial), Some((3.25, None, "lb")));
+        assert_eq!(convert(30.0, None, "ml", UnitSystem::Imperial), Some((2.0, None, "tbsp")));
+        assert_eq!(convert(2.0, None, "l", UnitSystem::Metric), None);
+        assert_eq!(convert(1.0, None, "pinch", UnitSystem::Metric), None);
+    }
+
+    #[test]
+    fn convert_keeps_small_quantities_their_size() {
+        assert_eq!(convert(70.0, None, "ml", UnitSystem::Imperial), Some((4.75, None, "tbsp")));
+        assert_eq!(convert(5.0, None, "ml", UnitSystem::Imperial), Some((1.0, None, "tsp")));
+        assert_eq!(convert(2.5, None, "ml", UnitSystem::Imperial), Some((0.5, None, "tsp")));
+        assert_eq!(convert(0.6, None, "ml", UnitSystem::Imperial), Some((0.125, None, "tsp")));
+        assert_eq!(convert(15.0, Some(20.0), "g", UnitSystem::Imperial), Some((0.5, Some(0.75), "oz")));
+        assert_eq!(convert(1.0, None, "ml", UnitSystem::Imperial), None);
+        assert_eq!(convert(3.0, None, "g", UnitSystem::Imperial), None);
+        assert_eq!(convert(1.0, Some(2.0), "g", UnitSystem::Imperial), None);
+        assert_eq!(convert(0.125, None, "tsp", UnitSystem::Metric), Some((0.6, None, "ml")));
+    }
+
+    #[test]
+    fn convert_temperatures_rewrites_temperatures_only() {
+        let imperial = |text| convert_temperatures(text, UnitSystem::Imperial);
+        assert_eq!(imperial("Bake at 180°C for 20 minutes"), "Bake at 355°F for 20 minutes");
+        assert_eq!(imperial("Heat to 200 degrees Celsius."), "Heat to 390°F.");
+        assert_eq!(imperial("180.5°C"), "355°F");
+        assert_eq!(imperial("Chill to -10°C"), "Chill to 14°F");
+        assert_eq!(imperial("Warm to 37 °C"), "Warm to 99°F");
+        assert_eq!(imperial("Cool 5-10 minutes, then chill at 4°C"), "Cool 5-10 minutes, then chill at 39°F");
+        assert_eq!(imperial("Add 2 cups and 1.5 l of water"), "Add 2 cups and 1.5 l of water");
+        assert_eq!(imperial("Cut into 4 cubes"), "Cut into 4 cubes");
+        assert_eq!(imperial("Step 2. Bake at 350°F"), "Step 2. Bake at 350°F");
+
+        let metric = |text| convert_temperatures(text, UnitSystem::Metric);
+        assert_eq!(metric("Preheat oven to 350F"), "Preheat oven to 175°C");
+        assert_eq!(metric("Freeze at 0 degrees Fahrenheit"), "Freeze at -18°C");
+        assert_eq!(metric("Cook 5 fish fillets"), "Cook 5 fish fillets");
+    }
+}
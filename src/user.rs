@@ -0,0 +1,93 @@
+use crate::*;
+
+/// What an account may do. Each role can do everything the ones before it
+/// can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Role {
+    Reader,
+    Contributor,
+    Editor,
+    Admin,
+}
+
+pub struct User {
+    pub id: i64,
+    pub password_hash: String,
+    pub role: Role,
+}
+
+/// Store a new account and return its id and role, which is always reader:
+/// admins are only made by [`promote_admin`]. Fails with a unique violation
+/// if the email is already registered.
+pub async fn add(
+    db: impl sqlx::SqliteExecutor<'_>,
+    full_name: &str,
+    email: &str,
+    password_hash: &str,
+) -> Result<(i64, Role), sqlx::Error> {
+    let inserted = sqlx::query!(
+        r#"INSERT INTO users (full_name, email, password_hash)
+        VALUES ($1, $2, $3)
+        RETURNING id, role AS "role: Role";"#,
+        full_name,
+        email,
+        password_hash,
+    )
+    .fetch_one(db)
+    .await?;
+    Ok((inserted.id, inserted.role))
+}
+
+pub async fn get_by_email(db: impl sqlx::SqliteExecutor<'_>, email: &str) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        r#"SELECT id, password_hash, role AS "role: Role" FROM users WHERE email = $1;"#,
+        email,
+    )
+    .fetch_optional(db)
+    .await
+}
+
+pub async fn get_email(db: &SqlitePool, user_id: i64) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!("SELECT email FROM users WHERE id = $1;", user_id)
+        .fetch_optional(db)
+        .await
+}
+
+pub async fn get_role(db: impl sqlx::SqliteExecutor<'_>, user_id: i64) -> Result<Option<Role>, sqlx::Error> {
+    sqlx::query_scalar!(r#"SELECT role AS "role: Role" FROM users WHERE id = $1;"#, user_id)
+        .fetch_optional(db)
+        .await
+}
+
+/// Change the role of an account. Returns `false` if there is no such
+/// account, or if it is the last admin and would stop being one.
+pub async fn set_role(db: impl sqlx::SqliteExecutor<'_>, user_id: i64, role: Role) -> Result<bool, sqlx::Error> {
+    let updated = sqlx::query!(
+        r#"UPDATE users SET role = $1
+        WHERE id = $2
+          AND ($1 = 'admin' OR role <> 'admin'
+            OR (SELECT COUNT(*) FROM users WHERE role = 'admin') > 1);"#,
+        role,
+        user_id,
+    )
+    .execute(db)
+    .await?;
+    Ok(updated.rows_affected() > 0)
+}
+
+/// Make the account registered under `email` an admin. Returns its id and
+/// previous role, or `None` if there is no such account.
+pub async fn promote_admin(conn: &mut sqlx::SqliteConnection, email: &str) -> Result<Option<(i64, Role)>, sqlx::Error> {
+    let Some(user) = get_by_email(&mut *conn, email).await? else {
+        return Ok(None);
+    };
+    if user.role != Role::Admin {
+        sqlx::query!("UPDATE users SET role = 'admin' WHERE id = $1;", user.id)
+            .execute(conn)
+            .await?;
+    }
+    Ok(Some((user.id, user.role)))
+}
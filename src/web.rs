@@ -1,27 +1,50 @@
 use crate::*;
 
+use axum::extract::Form;
+use axum_extra::extract::cookie::CookieJar;
+
 #[derive(Deserialize)]
 pub struct GetRecipeParams {
     id: Option<String>,
     ingredients: Option<String>,
+    units: Option<UnitSystem>,
+}
+
+fn recipe_uri(id: impl std::fmt::Display, units: Option<UnitSystem>) -> String {
+    match units {
+        Some(units) => format!("/?id={}&units={}", id, units),
+        None => format!("/?id={}", id),
+    }
 }
 
 pub async fn get_recipe(
     State(app_state): State<Arc<RwLock<AppState>>>,
+    jar: CookieJar,
     Query(params): Query<GetRecipeParams>,
 ) -> Result<response::Response, http::StatusCode> {
     let mut app_writer = app_state.write().await;
     let db = app_writer.db.clone();
+    let session = session::current(&db, &jar).await;
 
     // Specified.
     if let GetRecipeParams { id: Some(id), .. } = params {
         let recipe_result = recipe::get(&db, &id).await;
         let result = match recipe_result {
-            Ok((recipe, ingredients)) => {
-                let ingredients_string = ingredients.join(", ");
+            Ok((mut recipe, mut ingredients)) => {
+                if let Some(system) = params.units {
+                    for ingredient in &mut ingredients {
+                        ingredient.convert_units(system);
+                    }
+                    recipe.preparation = units::convert_temperatures(&recipe.preparation, system);
+                }
+                let ingredients_string = ingredients
+                    .iter()
+                    .map(|i| i.text.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
 
                 app_writer.current_recipe = recipe.clone();
-                let recipe = IndexTemplate::new(recipe.clone(), ingredients_string);
+                let recipe = IndexTemplate::new(recipe.clone(), ingredients_string, session);
                 Ok(response::Html(recipe.to_string()).into_response())
             }
             Err(e) => {
@@ -37,19 +60,21 @@ pub async fn get_recipe(
 
         let mut ingredients_string = String::new();
         for c in ingredients.chars() {
-            if c.is_alphabetic() || c == ',' {
+            if c.is_alphabetic() || c == ',' || c == ' ' {
                 let cl: String = c.to_lowercase().collect();
                 ingredients_string.push_str(&cl);
             }
         }
 
-        let recipe_result = recipe::get_by_ingredients(&db, ingredients_string.split(',')).await;
+        // Pick at random among the hundred best matches.
+        let recipe_result = recipe::get_by_ingredients(&db, ingredients_string.split(','), 100).await;
         match recipe_result {
-            Ok(Some(id)) => {
-                let uri = format!("/?id={}", id);
+            Ok(ids) if !ids.is_empty() => {
+                let id = ids[fastrand::usize(..ids.len())];
+                let uri = recipe_uri(id, params.units);
                 return Ok(response::Redirect::to(&uri).into_response());
             }
-            Ok(None) => {
+            Ok(_) => {
                 log::info!("recipe by ingredietns selection was empty");
             }
             Err(e) => {
@@ -62,15 +87,228 @@ pub async fn get_recipe(
     let recipe_result = recipe::get_random(&db).await;
     match recipe_result {
         Ok(id) => {
-            let uri = format!("/?id={}", id.to_string());
+            let uri = recipe_uri(id, params.units);
             Ok(response::Redirect::to(&uri).into_response())
         }
         Err(e) => {
             log::error!("recipe selection failed: {}", e);
             let ingredient_string = "empty".to_string();
             let recipe = app_writer.current_recipe.clone();
-            let recipe = IndexTemplate::new(recipe, ingredient_string);
+            let recipe = IndexTemplate::new(recipe, ingredient_string, session);
             Ok(response::Html(recipe.to_string()).into_response())
         }
     }
 }
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    csrf_token: String,
+    #[serde(flatten)]
+    login: authjwt::Login,
+}
+
+/// A form with nothing but its CSRF token, like the logout button.
+#[derive(Deserialize)]
+pub struct CsrfForm {
+    csrf_token: String,
+}
+
+/// The add recipe form as typed, kept to show again if it is refused.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RecipeForm {
+    csrf_token: String,
+    pub title: String,
+    pub category: String,
+    pub servings: String,
+    /// One ingredient per line.
+    pub ingredients: String,
+    pub preparation: String,
+}
+
+impl RecipeForm {
+    fn to_recipe(&self) -> Result<JsonRecipe, &'static str> {
+        let title = self.title.trim();
+        let category = self.category.trim();
+        let preparation = self.preparation.trim();
+        if title.is_empty() || category.is_empty() || preparation.is_empty() {
+            return Err("Title, category and preparation are required");
+        }
+        let servings = match self.servings.trim() {
+            "" => None,
+            servings => match servings.parse::<i64>() {
+                Ok(servings) if servings > 0 => Some(servings),
+                _ => return Err("Servings must be a whole number above zero"),
+            },
+        };
+        let ingredients: Vec<Ingredient> = self
+            .ingredients
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(Ingredient::parse)
+            .collect();
+        if ingredients.is_empty() {
+            return Err("At least one ingredient is required");
+        }
+        Ok(JsonRecipe::draft(
+            title.to_string(),
+            category.to_string(),
+            ingredients,
+            preparation.to_string(),
+            servings,
+        ))
+    }
+}
+
+fn bad_form_token() -> response::Response {
+    (http::StatusCode::FORBIDDEN, "403 Forbidden: invalid or missing form token").into_response()
+}
+
+pub async fn get_login(
+    State(app_state): State<SharedAppState>,
+    jar: CookieJar,
+) -> response::Response {
+    let app_reader = app_state.read().await;
+    if session::current(&app_reader.db, &jar).await.is_some() {
+        return response::Redirect::to("/").into_response();
+    }
+    let (csrf_token, jar) = session::csrf_token(None, jar);
+    let page = LoginTemplate::new(csrf_token, None);
+    (jar, response::Html(page.to_string())).into_response()
+}
+
+pub async fn post_login(
+    State(app_state): State<SharedAppState>,
+    throttle::ClientIp(ip): throttle::ClientIp,
+    audit::RequestId(request_id): audit::RequestId,
+    jar: CookieJar,
+    Form(form): Form<LoginForm>,
+) -> response::Response {
+    if !session::check_csrf(None, &jar, &form.csrf_token) {
+        return bad_form_token();
+    }
+    let app_reader = app_state.read().await;
+    let user = match authjwt::check_login(&app_reader, &form.login, ip, request_id.as_deref()).await {
+        Err(authjwt::AuthError::WrongCredentials) => {
+            let page = LoginTemplate::new(form.csrf_token, Some("Wrong email, password or code"));
+            return (http::StatusCode::UNAUTHORIZED, response::Html(page.to_string())).into_response();
+        }
+        Err(authjwt::AuthError::TotpRequired) => {
+            let page = LoginTemplate::new(form.csrf_token, Some("Enter the code from your authenticator app"));
+            return (http::StatusCode::UNAUTHORIZED, response::Html(page.to_string())).into_response();
+        }
+        Err(e) => return e.into_response(),
+        Ok(user) => user,
+    };
+    match session::start(&app_reader.db, jar, user.id).await {
+        Ok(jar) => (jar, response::Redirect::to("/")).into_response(),
+        Err(e) => {
+            log::warn!("session insert failed: {}", e);
+            http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn post_logout(
+    State(app_state): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    jar: CookieJar,
+    Form(form): Form<CsrfForm>,
+) -> response::Response {
+    let app_reader = app_state.read().await;
+    let Some(session) = session::current(&app_reader.db, &jar).await else {
+        return response::Redirect::to("/").into_response();
+    };
+    if !session::check_csrf(Some(&session), &jar, &form.csrf_token) {
+        return bad_form_token();
+    }
+    match session::end(&app_reader.db, jar, &session).await {
+        Ok(jar) => {
+            let event = audit::Event::new(audit::Action::Logout, request_id.as_deref())
+                .actor(Some(session.user_id));
+            audit::record(&app_reader.db, event).await;
+            (jar, response::Redirect::to("/")).into_response()
+        }
+        Err(e) => {
+            log::warn!("session delete failed: {}", e);
+            http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// The session of a browser that may add recipes, or the response to give
+/// one that may not.
+async fn contributor_session(db: &SqlitePool, jar: &CookieJar) -> Result<session::Session, response::Response> {
+    let Some(session) = session::current(db, jar).await else {
+        return Err(response::Redirect::to("/login").into_response());
+    };
+    if session.role < user::Role::Contributor {
+        return Err((http::StatusCode::FORBIDDEN, "403 Forbidden: contributors only").into_response());
+    }
+    Ok(session)
+}
+
+pub async fn get_add_recipe(
+    State(app_state): State<SharedAppState>,
+    jar: CookieJar,
+) -> response::Response {
+    let app_reader = app_state.read().await;
+    let session = match contributor_session(&app_reader.db, &jar).await {
+        Err(response) => return response,
+        Ok(session) => session,
+    };
+    let page = AddRecipeTemplate::new(session.csrf_token, RecipeForm::default(), None);
+    response::Html(page.to_string()).into_response()
+}
+
+pub async fn post_add_recipe(
+    State(app_state): State<SharedAppState>,
+    audit::RequestId(request_id): audit::RequestId,
+    jar: CookieJar,
+    Form(form): Form<RecipeForm>,
+) -> response::Response {
+    let app_reader = app_state.read().await;
+    let session = match contributor_session(&app_reader.db, &jar).await {
+        Err(response) => return response,
+        Ok(session) => session,
+    };
+    if !session::check_csrf(Some(&session), &jar, &form.csrf_token) {
+        return bad_form_token();
+    }
+    let recipe = match form.to_recipe() {
+        Err(error) => {
+            let page = AddRecipeTemplate::new(session.csrf_token, form, Some(error));
+            return (http::StatusCode::BAD_REQUEST, response::Html(page.to_string())).into_response();
+        }
+        Ok(recipe) => recipe,
+    };
+    let recipe_id = match store_recipe(&app_reader.db, recipe, session.user_id, request_id.as_deref()).await {
+        Err(e) => {
+            log::warn!("recipe insert failed: {}", e);
+            let page = AddRecipeTemplate::new(session.csrf_token, form, Some("The recipe could not be stored"));
+            return (http::StatusCode::INTERNAL_SERVER_ERROR, response::Html(page.to_string())).into_response();
+        }
+        Ok(recipe_id) => recipe_id,
+    };
+    response::Redirect::to(&recipe_uri(recipe_id, None)).into_response()
+}
+
+/// Add a recipe by `author_id` together with its audit record.
+async fn store_recipe(
+    db: &SqlitePool,
+    recipe: JsonRecipe,
+    author_id: i64,
+    request_id: Option<&str>,
+) -> Result<i64, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let recipe_id = recipe::add(&mut tx, recipe, Some(author_id)).await?;
+    let after = recipe::snapshot(&mut tx, recipe_id).await;
+    let event = audit::Event::new(audit::Action::RecipeCreate, request_id)
+        .actor(Some(author_id))
+        .target(recipe_id)
+        .diff(audit::diff(None, after.as_ref()));
+    audit::write(&mut *tx, event).await?;
+    tx.commit().await?;
+    Ok(recipe_id)
+}